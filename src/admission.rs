@@ -0,0 +1,222 @@
+use std::error::Error;
+
+use nix::unistd::getuid;
+use slurm_spank::{Context, SpankHandle};
+
+use crate::edf::define_edf_default_envvar;
+use crate::jobinfo::{JobInfo, job_info};
+use crate::plugstack::plugstack_partition_allowed;
+use crate::{SpankSkyBox, plugin_err, skybox_log_debug, skybox_log_user, spank_getenv};
+
+/// Check the admission policy of the site, if a container is requested.
+///
+/// An explicit `--edf` is rejected with an error, a default EDF is just
+/// dropped for jobs outside of the policy.
+pub(crate) fn admission_enforce(
+    ssb: &mut SpankSkyBox,
+    spank: &mut SpankHandle,
+) -> Result<(), Box<dyn Error>> {
+    if ssb.args.edf.is_none() {
+        return Ok(());
+    }
+
+    if let Err(e) = admission_check(ssb, spank) {
+        if ssb.args.edf_default.is_none() {
+            return Err(e);
+        }
+
+        skybox_log_debug!("not using default EDF: {e}");
+        ssb.args.edf = None;
+        ssb.config.skybox_enabled = false;
+        return Ok(());
+    }
+
+    // The nodes only see a default EDF once it is admitted on submission.
+    if let Ok(Context::Local | Context::Allocator) = spank.context()
+        && let (Some(name), Some(source)) = (&ssb.args.edf, &ssb.args.edf_default)
+    {
+        define_edf_default_envvar(name);
+        skybox_log_user!(
+            "no --edf given, using the {source} default EDF \"{name}\" (--no-edf to opt out)"
+        );
+    }
+
+    Ok(())
+}
+
+fn admission_check(ssb: &mut SpankSkyBox, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+    let policy = ssb.skybox_config.admission.clone();
+    let required = ssb.plugstack.required_partitions.clone();
+
+    let job_checked = !required.is_empty()
+        || !policy.partitions.is_empty()
+        || !policy.qos.is_empty()
+        || !policy.accounts.is_empty();
+
+    // Before the allocation exists the job values may be unknown, the user
+    // submitting is trusted with their own environment. The nodes read the
+    // job values (see job_info) and reject what they cannot determine.
+    let remote = matches!(spank.context(), Ok(Context::Remote));
+
+    let info = if !job_checked {
+        JobInfo::default()
+    } else if remote {
+        match job_info(ssb, spank) {
+            Ok(i) => i,
+            Err(e) => return plugin_err(&format!("--edf is refused, cannot determine the job values: {e}")),
+        }
+    } else {
+        JobInfo {
+            partition: submit_partition(spank),
+            qos: spank_getenv(spank, "SLURM_JOB_QOS"),
+            account: spank_getenv(spank, "SLURM_JOB_ACCOUNT"),
+        }
+    };
+
+    if !required.is_empty() {
+        if info.partition.is_empty() && remote {
+            return plugin_err("--edf is refused, cannot find the job partition");
+        }
+        if !info.partition.is_empty()
+            && !plugstack_partition_allowed(&ssb.plugstack, &info.partition)
+        {
+            return plugin_err(&format!(
+                "--edf is not available on partition {}",
+                info.partition
+            ));
+        }
+    }
+
+    let checks = [
+        ("partition", &policy.partitions, info.partition),
+        ("QOS", &policy.qos, info.qos),
+        ("account", &policy.accounts, info.account),
+    ];
+
+    for (what, allowed, value) in checks {
+        if allowed.is_empty() {
+            continue;
+        }
+
+        if value.is_empty() {
+            if remote {
+                return plugin_err(&format!("--edf is refused, cannot find the job {what}"));
+            }
+            continue;
+        }
+
+        if !allowed.contains(&value) {
+            return plugin_err(&format!(
+                "--edf is not allowed for {what} {value}, allowed: {}",
+                allowed.join(", ")
+            ));
+        }
+    }
+
+    if !policy.groups.is_empty() {
+        let uid = match spank.context()? {
+            Context::Remote => spank.job_uid()?,
+            _ => getuid().as_raw(),
+        };
+
+        let groups = admission_user_groups(uid);
+        if !groups.iter().any(|g| policy.groups.contains(g)) {
+            return plugin_err(&format!(
+                "--edf is only allowed for members of {}",
+                policy.groups.join(", ")
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Partition asked for at submission by srun, sbatch or salloc, empty when
+/// the job goes to the default partition or it cannot be told. Only the
+/// nodes know the partition for sure.
+pub(crate) fn submit_partition(spank: &mut SpankHandle) -> String {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(p) = argv_partition(&args) {
+        return p;
+    }
+
+    [
+        "SLURM_JOB_PARTITION",
+        "SBATCH_PARTITION",
+        "SALLOC_PARTITION",
+        "SLURM_PARTITION",
+    ]
+    .iter()
+    .map(|v| spank_getenv(spank, v))
+    .find(|p| !p.is_empty())
+    .unwrap_or_default()
+}
+
+// "-p x", "-px", "--partition x" or "--partition=x", options come before the
+// command or the batch script.
+fn argv_partition(args: &[String]) -> Option<String> {
+    let mut it = args.iter();
+
+    while let Some(a) = it.next() {
+        if a == "--" {
+            break;
+        }
+        if a == "-p" || a == "--partition" {
+            return it.next().filter(|v| !v.starts_with('-')).cloned();
+        }
+        if let Some(p) = a.strip_prefix("--partition=") {
+            return Some(String::from(p));
+        }
+        if let Some(p) = a.strip_prefix("-p")
+            && !a.starts_with("--")
+            && !p.is_empty()
+        {
+            return Some(String::from(p));
+        }
+    }
+
+    None
+}
+
+// Group names are taken from the system, not from the job environment.
+fn admission_user_groups(uid: u32) -> Vec<String> {
+    let user = match users::get_user_by_uid(uid) {
+        Some(u) => u,
+        None => return vec![],
+    };
+
+    match users::get_user_groups(user.name(), user.primary_group_id()) {
+        Some(groups) => groups
+            .iter()
+            .map(|g| g.name().to_string_lossy().to_string())
+            .collect(),
+        None => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::argv_partition;
+
+    fn partition(args: &[&str]) -> Option<String> {
+        let args: Vec<String> = args.iter().map(|a| String::from(*a)).collect();
+        argv_partition(&args)
+    }
+
+    #[test]
+    fn partition_forms() {
+        let p = Some(String::from("debug"));
+        assert_eq!(partition(&["-p", "debug", "app"]), p);
+        assert_eq!(partition(&["-pdebug", "app"]), p);
+        assert_eq!(partition(&["--partition", "debug", "app"]), p);
+        assert_eq!(partition(&["-N", "2", "--partition=debug", "app"]), p);
+    }
+
+    #[test]
+    fn partition_absent() {
+        assert_eq!(partition(&["--pty", "bash"]), None);
+        assert_eq!(partition(&["-N", "2", "--", "app", "-p", "x"]), None);
+        assert_eq!(partition(&["-p"]), None);
+        assert_eq!(partition(&["-p", "--pty"]), None);
+    }
+}
@@ -1,3 +1,4 @@
+use nix::unistd::{getgid, getuid};
 use std::env;
 use std::error::Error;
 use std::ffi::OsStr;
@@ -5,8 +6,10 @@ use std::path::Path;
 
 use slurm_spank::{SpankHandle, spank_log_user};
 
+use crate::admission::admission_enforce;
 use crate::args::*;
 use crate::config::*;
+use crate::constraints::edf_constraints_check;
 use crate::edf::*;
 use crate::skybox_log_user;
 use crate::{SpankSkyBox, plugin_err, skybox_log_error};
@@ -39,6 +42,11 @@ pub(crate) fn alloc_init_post_opt(
         return Ok(());
     }
 
+    if let Err(e) = admission_enforce(plugin, spank) {
+        skybox_log_user!("{e}");
+        return Err(e);
+    }
+
     match load_edf(plugin, spank) {
         Ok(o) => (),
         Err(e) => {
@@ -47,6 +55,18 @@ pub(crate) fn alloc_init_post_opt(
         }
     }
 
+    if let Some(edf) = &plugin.edf
+        && let Err(e) = edf_constraints_check(
+            &plugin.skybox_config.constraints,
+            edf,
+            getuid().as_raw(),
+            getgid().as_raw(),
+        )
+    {
+        skybox_log_user!("{e}");
+        return Err(e);
+    }
+
     update_config_by_user(&mut plugin.config, plugin.edf.clone().unwrap())?;
     set_remaining_default_args(plugin)?;
 
@@ -126,6 +146,16 @@ fn alloc_load_config(
         }
     };
 
+    match setup_skybox_config(plugin, spank) {
+        Ok(_) => {}
+        Err(e) => {
+            plugin.config.skybox_enabled = false;
+            skybox_log_error!("{}", e);
+            skybox_log_error!("plugin is disabled");
+            return plugin_err("plugin is disabled");
+        }
+    }
+
     // Set Config
     match setup_config(&config, plugin) {
         Ok(_) => {}
@@ -1,14 +1,19 @@
 use serde::{Deserialize, Serialize};
-use slurm_spank::{SpankHandle, SpankOption};
+use slurm_spank::{Context, SpankHandle, SpankOption};
 //use std::collections::HashMap;
 use std::error::Error;
 
+use crate::edf::{clear_edf_default_envvar, edf_default};
 use crate::{SpankSkyBox, get_plugin_name, plugin_err};
 //use raster::mount::{SarusMounts, sarus_mounts_from_strings};
 
 #[derive(Default, Serialize, Deserialize)]
 pub(crate) struct SkyBoxArgs {
     pub(crate) edf: Option<String>,
+    pub(crate) edf_default: Option<String>,
+    pub(crate) no_edf: bool,
+    pub(crate) mount_home: Option<bool>,
+    pub(crate) debug: bool,
 }
 
 pub(crate) struct SpankArg {
@@ -88,28 +93,6 @@ pub(crate) fn register_plugin_args(spank: &mut SpankHandle) -> Result<(), Box<dy
         },
     );
 
-    opts = add_arg(
-        opts,
-        SpankArg {
-            name: String::from("container-mount-home"),
-            value: String::from(""),
-            usage: String::from(
-                "bind mount the user's home directory. System-level enroot settings might cause this directory to be already-mounted.",
-            ),
-            has_arg: false,
-        },
-    );
-
-    opts = add_arg(
-        opts,
-        SpankArg {
-            name: String::from("no-container-mount-home"),
-            value: String::from(""),
-            usage: String::from("do not bind mount the user's home directory"),
-            has_arg: false,
-        },
-    );
-
     opts = add_arg(
         opts,
         SpankArg {
@@ -213,6 +196,49 @@ pub(crate) fn register_plugin_args(spank: &mut SpankHandle) -> Result<(), Box<dy
             has_arg: true,
         },
     );
+
+    opts = add_arg(
+        opts,
+        SpankArg {
+            name: String::from("no-edf"),
+            value: String::from(""),
+            usage: String::from("do not use the default Environment Definition File of the site."),
+            has_arg: false,
+        },
+    );
+    opts = add_arg(
+        opts,
+        SpankArg {
+            name: String::from("container-mount-home"),
+            value: String::from(""),
+            usage: String::from(
+                "bind mount the user's home directory. Site default mounts might cause this directory to be already-mounted.",
+            ),
+            has_arg: false,
+        },
+    );
+
+    opts = add_arg(
+        opts,
+        SpankArg {
+            name: String::from("no-container-mount-home"),
+            value: String::from(""),
+            usage: String::from("do not bind mount the user's home directory"),
+            has_arg: false,
+        },
+    );
+
+    opts = add_arg(
+        opts,
+        SpankArg {
+            name: String::from("edf-debug"),
+            value: String::from(""),
+            usage: String::from(
+                "write the skybox debug log of the step, including podman commands and their output, to a file in the submit directory.",
+            ),
+            has_arg: false,
+        },
+    );
     /*
     opts = add_arg(
         opts,
@@ -242,22 +268,6 @@ pub(crate) fn register_plugin_args(spank: &mut SpankHandle) -> Result<(), Box<dy
     Ok(())
 }
 /*
-pub(crate) fn set_arg_mount_home(ssb: &mut SpankSkyBox, value: bool) -> Result<(), Box<dyn Error>> {
-    match ssb.container_mount_home {
-        Some(_) => {
-            if ssb.container_mount_home != Some(value) {
-                plugin_err(
-                    "both --container-mount-home and --no-container-mount-home were specified",
-                )?
-            }
-        }
-        None => {
-            ssb.container_mount_home = Some(value);
-        }
-    }
-    Ok(())
-}
-
 pub(crate) fn set_arg_remap_root(ssb: &mut SpankSkyBox, value: bool) -> Result<(), Box<dyn Error>> {
     match ssb.container_remap_root {
         Some(_) => {
@@ -324,6 +334,34 @@ pub(crate) fn set_arg_edf(ssb: &mut SpankSkyBox, value: String) -> Result<(), Bo
     ssb.args.edf = Some(value);
     Ok(())
 }
+
+// Without --edf, use the default EDF of the user, partition or site if any.
+// The user is told once admission has accepted it.
+fn set_arg_default_edf(ssb: &mut SpankSkyBox, spank: &mut SpankHandle) {
+    let (name, source) = match edf_default(ssb, spank) {
+        Some(d) => d,
+        None => return,
+    };
+
+    ssb.args.edf = Some(name);
+    ssb.args.edf_default = Some(source);
+}
+
+pub(crate) fn set_arg_mount_home(ssb: &mut SpankSkyBox, value: bool) -> Result<(), Box<dyn Error>> {
+    match ssb.args.mount_home {
+        Some(_) => {
+            if ssb.args.mount_home != Some(value) {
+                plugin_err(
+                    "both --container-mount-home and --no-container-mount-home were specified",
+                )?
+            }
+        }
+        None => {
+            ssb.args.mount_home = Some(value);
+        }
+    }
+    Ok(())
+}
 /*
 pub(crate) fn set_arg_image(ssb: &mut SpankSkyBox, value: String) -> Result<(), Box<dyn Error>> {
     if value == "" {
@@ -455,14 +493,6 @@ pub(crate) fn load_plugin_args(
         let _ = set_arg_save(ssb, arg_value)?;
     }
 
-    if spank.is_option_set("container-mount-home") {
-        let _ = set_arg_mount_home(ssb, true)?;
-    }
-
-    if spank.is_option_set("no-container-mount-home") {
-        let _ = set_arg_mount_home(ssb, false)?;
-    }
-
     if spank.is_option_set("container-remap-root") {
         let _ = set_arg_remap_root(ssb, true)?;
     }
@@ -523,10 +553,41 @@ pub(crate) fn load_plugin_args(
             .map(|s| s.to_string())
             .unwrap();
         let _ = set_arg_edf(ssb, arg_value)?;
-    } else {
+    }
+
+    if spank.is_option_set("no-edf") {
+        if ssb.args.edf.is_some() {
+            plugin_err("both --edf and --no-edf were specified")?
+        }
+        ssb.args.no_edf = true;
+    }
+
+    // A step inherits the default EDF of the job it runs in, the nodes must
+    // only see the one picked for this step.
+    if let Ok(Context::Local | Context::Allocator) = spank.context() {
+        clear_edf_default_envvar();
+    }
+
+    if ssb.args.edf.is_none() && !ssb.args.no_edf {
+        set_arg_default_edf(ssb, spank);
+    }
+
+    if ssb.args.edf.is_none() {
         ssb.config.skybox_enabled = false;
     }
 
+    if spank.is_option_set("container-mount-home") {
+        set_arg_mount_home(ssb, true)?;
+    }
+
+    if spank.is_option_set("no-container-mount-home") {
+        set_arg_mount_home(ssb, false)?;
+    }
+
+    if spank.is_option_set("edf-debug") {
+        ssb.args.debug = true;
+    }
+
     /*
     if spank.is_option_set("dump-environment") {
         let _ = set_arg_dump_environment(ssb, true)?;
@@ -1,39 +1,464 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
+use std::fmt;
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::Instant;
 
-use slurm_spank::{SpankHandle, spank_log_user, spank_log_error};
+use nix::unistd::{Gid, Uid, setfsgid, setfsuid};
+use slurm_spank::{Context, SpankHandle};
 
 use raster::config::remove_sarus_annotations;
 use raster::hook_run;
 use raster::*;
 
-use crate::{SpankSkyBox, get_job_env, plugin_err, skybox_log_error, skybox_log_debug};
+use crate::constraints::{edf_constraints_apply_readonly, edf_constraints_check};
+use crate::edf::user_home;
+use crate::jobinfo::{JobInfo, job_info};
+use crate::logging::{log_set_structured, log_set_threshold};
+use crate::plugstack::{plugstack_apply_config, plugstack_args, table_insert_dotted};
+use crate::report::{report_command, report_configure};
+use crate::{
+    SpankSkyBox, get_job_env, plugin_err, plugin_string, skybox_log_debug, skybox_log_error,
+    skybox_log_info, skybox_log_user,
+};
+
+/// Skybox specific settings, read from the `[skybox]` table of the config file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct SkyBoxConfig {
+    pub(crate) workdir_fallback: Vec<WorkdirSource>,
+    pub(crate) default_mounts: DefaultMounts,
+    pub(crate) slurm_client: SlurmClient,
+    pub(crate) tracking: TrackingConfig,
+    pub(crate) log_format: LogFormat,
+    pub(crate) debug_log_dir: String,
+    pub(crate) command_output: CommandOutputConfig,
+    pub(crate) hooks: HooksConfig,
+    pub(crate) layers: ConfigLayers,
+    pub(crate) admission: Admission,
+    pub(crate) constraints: EdfConstraints,
+    pub(crate) verify_edf: bool,
+    pub(crate) edf_source: EdfSource,
+    pub(crate) default_edf: DefaultEdf,
+    pub(crate) health: HealthConfig,
+    pub(crate) stop: StopConfig,
+    pub(crate) job_info: JobInfoConfig,
+}
 
-pub(crate) fn resolve_config_path(spank: &mut SpankHandle) -> Option<PathBuf> {
-    let plugin_argv = spank.plugin_argv();
-
-    let mut config_path: Option<PathBuf> = None;
-
-    for args in plugin_argv.iter() {
-        for arg in args.iter() {
-            let mut fields = arg.split("=");
-            let key = match fields.next() {
-                Some(k) => k,
-                None => continue,
-            };
-            let value = match fields.next() {
-                Some(v) => v,
-                None => continue,
-            };
-
-            if key == "config_path" {
-                config_path = Some(PathBuf::from(value));
-            }
+impl Default for SkyBoxConfig {
+    fn default() -> Self {
+        SkyBoxConfig {
+            workdir_fallback: vec![
+                WorkdirSource::Edf,
+                WorkdirSource::Pwd,
+                WorkdirSource::Image,
+                WorkdirSource::Home,
+                WorkdirSource::Root,
+            ],
+            default_mounts: DefaultMounts::default(),
+            slurm_client: SlurmClient::default(),
+            tracking: TrackingConfig::default(),
+            log_format: LogFormat::Plain,
+            debug_log_dir: String::from(""),
+            command_output: CommandOutputConfig::default(),
+            hooks: HooksConfig::default(),
+            layers: ConfigLayers::default(),
+            admission: Admission::default(),
+            constraints: EdfConstraints::default(),
+            verify_edf: false,
+            edf_source: EdfSource::Env,
+            default_edf: DefaultEdf::default(),
+            health: HealthConfig::default(),
+            stop: StopConfig::default(),
+            job_info: JobInfoConfig::default(),
+        }
+    }
+}
+
+/// EDF used when a job gives no --edf: the user's ~/.edf/default.toml if
+/// `user` allows it, then the one of the job partition, then `site`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct DefaultEdf {
+    pub(crate) site: String,
+    pub(crate) partitions: HashMap<String, String>,
+    pub(crate) user: bool,
+}
+
+impl Default for DefaultEdf {
+    fn default() -> Self {
+        DefaultEdf {
+            site: String::from(""),
+            partitions: HashMap::new(),
+            user: true,
+        }
+    }
+}
+
+/// Node health check, run at slurmd start and optionally in every job epilog.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct HealthConfig {
+    pub(crate) enabled: bool,
+    pub(crate) epilog: bool,
+    pub(crate) marker_path: String,
+    pub(crate) timeout_ms: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            enabled: true,
+            epilog: false,
+            marker_path: String::from("/run/skybox/unhealthy"),
+            timeout_ms: 30000,
+        }
+    }
+}
+
+/// Where the nodes get the partition, QOS and account of a job: the step
+/// environment set by Slurm, or slurmctld with `scontrol`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct JobInfoConfig {
+    pub(crate) source: JobInfoSource,
+    pub(crate) scontrol: String,
+    pub(crate) timeout_ms: u64,
+}
+
+impl Default for JobInfoConfig {
+    fn default() -> Self {
+        JobInfoConfig {
+            source: JobInfoSource::Env,
+            scontrol: String::from("/usr/bin/scontrol"),
+            timeout_ms: 10000,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum JobInfoSource {
+    Env,
+    Slurmctld,
+}
+
+/// Container stop at the end of the step: how long the container process
+/// gets to exit after SIGCONT and SIGTERM together before it is killed, and
+/// how long podman stop and rm get afterwards.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct StopConfig {
+    pub(crate) grace_period_ms: u64,
+    pub(crate) cleanup_timeout_ms: u64,
+}
+
+impl Default for StopConfig {
+    fn default() -> Self {
+        StopConfig {
+            grace_period_ms: 10000,
+            cleanup_timeout_ms: 10000,
+        }
+    }
+}
+
+/// Where compute nodes get the EDF from: the rendering of the submit side
+/// in SLURM_EDF_EXPANDED, or their own rendering with the former as fallback.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum EdfSource {
+    Env,
+    Render,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum LogFormat {
+    Plain,
+    Json,
+}
+
+/// Site policy for mounts added to every EDF at render time.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct DefaultMounts {
+    pub(crate) home: bool,
+    pub(crate) submit_dir: bool,
+    pub(crate) scratch_env: String,
+    pub(crate) slurm: bool,
+}
+
+/// Host paths mounted read-only when an EDF opts into nested Slurm steps.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct SlurmClient {
+    pub(crate) paths: Vec<String>,
+}
+
+impl Default for SlurmClient {
+    fn default() -> Self {
+        let paths = [
+            "/usr/bin/sacct",
+            "/usr/bin/salloc",
+            "/usr/bin/sbatch",
+            "/usr/bin/scancel",
+            "/usr/bin/scontrol",
+            "/usr/bin/sinfo",
+            "/usr/bin/squeue",
+            "/usr/bin/srun",
+            "/usr/lib64/slurm",
+            "/etc/slurm",
+            "/var/run/munge",
+        ];
+        SlurmClient {
+            paths: paths.iter().map(|p| String::from(*p)).collect(),
         }
     }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum WorkdirSource {
+    Edf,
+    Pwd,
+    Image,
+    Home,
+    Root,
+}
+
+/// Where tracking records go, `tracking_tool` is only used by the command sink.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct TrackingConfig {
+    pub(crate) sink: TrackingSinkKind,
+    pub(crate) path: String,
+    pub(crate) timeout_ms: u64,
+    pub(crate) fail_policy: FailPolicy,
+    pub(crate) redact: RedactConfig,
+}
+
+impl Default for TrackingConfig {
+    fn default() -> Self {
+        TrackingConfig {
+            sink: TrackingSinkKind::Command,
+            path: String::from(""),
+            timeout_ms: 5000,
+            fail_policy: FailPolicy::Warn,
+            redact: RedactConfig::default(),
+        }
+    }
+}
+
+/// Secrets masked in tracked EDF content and argv: `keys` are case insensitive
+/// globs on variable and annotation names, `patterns` are regexes on values.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct RedactConfig {
+    pub(crate) keys: Vec<String>,
+    pub(crate) patterns: Vec<String>,
+    pub(crate) mask: String,
+}
+
+impl Default for RedactConfig {
+    fn default() -> Self {
+        let keys = [
+            "*TOKEN*",
+            "*SECRET*",
+            "*PASSWORD*",
+            "*PASSWD*",
+            "*CREDENTIAL*",
+            "*API_KEY*",
+            "*PRIVATE_KEY*",
+        ];
+        RedactConfig {
+            keys: keys.iter().map(|k| String::from(*k)).collect(),
+            patterns: vec![],
+            mask: String::from("***"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TrackingSinkKind {
+    Command,
+    File,
+    Syslog,
+    Socket,
+}
+
+/// How much of the output of external commands is echoed, and where.
+/// `verbosity` overrides the call site default by command prefix,
+/// e.g. "podman pull" or "parallax_imagestore_create hook".
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct CommandOutputConfig {
+    pub(crate) max_lines: usize,
+    pub(crate) max_bytes: usize,
+    pub(crate) verbosity: HashMap<String, CommandVerbosity>,
+}
+
+impl Default for CommandOutputConfig {
+    fn default() -> Self {
+        CommandOutputConfig {
+            max_lines: 100,
+            max_bytes: 16384,
+            verbosity: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CommandVerbosity {
+    Debug,
+    User,
+    Error,
+}
+
+/// Who may use containers, an empty list allows everything. Users must match
+/// every non empty list, and be member of one of `groups`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct Admission {
+    pub(crate) partitions: Vec<String>,
+    pub(crate) qos: Vec<String>,
+    pub(crate) accounts: Vec<String>,
+    pub(crate) groups: Vec<String>,
+}
+
+/// Limits on user EDFs, empty lists allow everything. Annotation lists are
+/// globs on the annotation name, mount prefixes are host paths.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct EdfConstraints {
+    pub(crate) mount_source_prefixes: Vec<String>,
+    pub(crate) forbidden_mount_flags: Vec<String>,
+    pub(crate) readonly_mount_prefixes: Vec<String>,
+    pub(crate) annotations_allow: Vec<String>,
+    pub(crate) annotations_deny: Vec<String>,
+    pub(crate) image_registries: Vec<String>,
+}
+
+/// Overrides of the main configuration, applied in order over the system
+/// file: partition, account, then the user file restricted to `user_keys`.
+/// `user_file` is relative to the job user home, no keys means no user layer.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct ConfigLayers {
+    pub(crate) partition: HashMap<String, toml::Table>,
+    pub(crate) account: HashMap<String, toml::Table>,
+    pub(crate) user_file: String,
+    pub(crate) user_keys: Vec<String>,
+}
+
+impl Default for ConfigLayers {
+    fn default() -> Self {
+        ConfigLayers {
+            partition: HashMap::new(),
+            account: HashMap::new(),
+            user_file: String::from(".config/skybox/config.toml"),
+            user_keys: vec![],
+        }
+    }
+}
+
+/// A configuration layer that cannot be applied. Unlike other configuration
+/// errors, which disable skybox, it fails the step: the job asked for a
+/// container and would otherwise run on the host.
+#[derive(Debug)]
+pub(crate) struct LayerError(String);
+
+impl fmt::Display for LayerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for LayerError {}
+
+/// Failure policy of the site lifecycle hooks, `policies` overrides
+/// `fail_policy` by hook point, e.g. "pre_start".
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct HooksConfig {
+    pub(crate) fail_policy: FailPolicy,
+    pub(crate) policies: HashMap<String, FailPolicy>,
+}
+
+impl Default for HooksConfig {
+    fn default() -> Self {
+        HooksConfig {
+            fail_policy: FailPolicy::Warn,
+            policies: HashMap::new(),
+        }
+    }
+}
 
-    config_path
+/// What a failing optional component (tracking, hooks) does to the step.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum FailPolicy {
+    Ignore,
+    Warn,
+    Fail,
+}
+
+pub(crate) fn load_skybox_config(
+    config_path: Option<PathBuf>,
+    overrides: &[(String, toml::Value)],
+) -> Result<SkyBoxConfig, Box<dyn Error>> {
+    let mut skybox = toml::Table::new();
+
+    if let Some(path) = config_path {
+        let content = std::fs::read_to_string(&path)?;
+        let table: toml::Table = toml::from_str(&content)?;
+
+        if let Some(toml::Value::Table(t)) = table.get("skybox") {
+            skybox = t.clone();
+        }
+    }
+
+    for (key, value) in overrides.iter() {
+        table_insert_dotted(&mut skybox, key, value.clone());
+    }
+
+    Ok(toml::Value::Table(skybox).try_into()?)
+}
+
+pub(crate) fn setup_skybox_config(
+    plugin: &mut SpankSkyBox,
+    spank: &mut SpankHandle,
+) -> Result<(), Box<dyn Error>> {
+    plugin.plugstack = plugstack_args(spank);
+
+    if let Ok(Context::Slurmd) = spank.context() {
+        for w in plugin.plugstack.warnings.iter() {
+            skybox_log_info!("warning: {w}");
+        }
+    }
+    if let Some(level) = plugin.plugstack.log_level {
+        log_set_threshold(level);
+    }
+
+    let config_path = plugin.plugstack.config_path.clone();
+    plugin.skybox_config = match load_skybox_config(config_path, &plugin.plugstack.skybox) {
+        Ok(c) => c,
+        Err(e) => {
+            return plugin_err(&format!("cannot load [skybox] configuration: {e}"));
+        }
+    };
+
+    log_set_structured(plugin.skybox_config.log_format == LogFormat::Json);
+    report_configure(&plugin.skybox_config.command_output);
+
+    Ok(())
+}
+
+pub(crate) fn resolve_config_path(spank: &mut SpankHandle) -> Option<PathBuf> {
+    plugstack_args(spank).config_path
 }
 
 pub(crate) fn plugin_enabled_in_config(
@@ -45,7 +470,7 @@ pub(crate) fn plugin_enabled_in_config(
     // Do not expand variables
     let config = load_config_path(config_path, VarExpand::Never, &None)?;
 
-    return Ok(config.skybox_enabled);
+    Ok(plugstack_args(spank).enabled.unwrap_or(config.skybox_enabled))
 }
 
 /*
@@ -73,8 +498,15 @@ pub(crate) fn load_config(
 pub(crate) fn setup_imagestore(config: &Config) -> Result<(), Box<dyn Error>> {
     let imagestore = &config.parallax_imagestore;
 
+    let t0 = Instant::now();
     match hook_run(config, "parallax_imagestore_create", vec![imagestore])? {
-        Some(ec) => log_hook_ec(ec, "parallax_imagestore_create hook"),
+        Some(ec) => report_command(
+            &ec.command,
+            &ec.output,
+            "parallax_imagestore_create hook",
+            CommandVerbosity::User,
+            t0.elapsed(),
+        ),
         None => {},
     }
 
@@ -91,6 +523,10 @@ pub(crate) fn setup_config(
     config: &Config,
     plugin: &mut SpankSkyBox,
 ) -> Result<(), Box<dyn Error>> {
+    let config = &plugstack_apply_config(&plugin.plugstack, config)?;
+    for key in plugin.plugstack.config.keys() {
+        plugin.config_sources.insert(key.clone(), String::from("plugstack"));
+    }
     plugin.config = config.clone();
 
     if config.parallax_imagestore == "" {
@@ -123,9 +559,23 @@ pub(crate) fn setup_config(
         return plugin_err("cannot find podman_tmp_path");
     }
 
-    if config.tracking_enabled && config.tracking_tool == "" {
-        plugin.config.tracking_enabled = false;
-        return plugin_err("cannot find tracking_tool");
+    if config.tracking_enabled {
+        let tracking = &plugin.skybox_config.tracking;
+        match tracking.sink {
+            TrackingSinkKind::Command => {
+                if config.tracking_tool.is_empty() {
+                    plugin.config.tracking_enabled = false;
+                    return plugin_err("cannot find tracking_tool");
+                }
+            }
+            TrackingSinkKind::File | TrackingSinkKind::Socket => {
+                if tracking.path.is_empty() {
+                    plugin.config.tracking_enabled = false;
+                    return plugin_err("cannot find [skybox.tracking] path");
+                }
+            }
+            TrackingSinkKind::Syslog => {}
+        }
     }
 
     Ok(())
@@ -139,8 +589,8 @@ pub(crate) fn render_user_job_config(
     let je = &Some(get_job_env(spank));
     //let job_config = raster::load_config_path(config_path, &Some(true), &je)?;
 
-    // force variable expansion -> &Some(true)
-    let mut job_config = match load_config_path(config_path, VarExpand::Must, &je) {
+    // Variables are expanded once the layers are merged, they may use them too.
+    let system_config = match load_config_path(config_path, VarExpand::Never, &None) {
         Ok(cfg) => cfg,
         Err(e) => {
             plugin.config.skybox_enabled = false;
@@ -159,6 +609,40 @@ pub(crate) fn render_user_job_config(
             return plugin_err("plugin is disabled");
         }
     };
+    // The job asked for a container, don't run it on the host instead.
+    let layered_config = match config_apply_layers(plugin, spank, system_config) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            let msg = format!("cannot apply configuration layers: {e}");
+            skybox_log_error!("{msg}");
+            skybox_log_user!("{msg}");
+            return Err(Box::new(LayerError(plugin_string(&msg))));
+        }
+    };
+
+    // force variable expansion -> &Some(true)
+    let mut job_config = match config_expand(&layered_config, je) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            plugin.config.skybox_enabled = false;
+            skybox_log_error!("{}", e);
+            skybox_log_error!("Error on configuration loading");
+            skybox_log_error!("plugin is disabled");
+            return plugin_err("plugin is disabled");
+        }
+    };
+
+    let (uid, gid) = match &plugin.job {
+        Some(j) => (j.uid, j.gid),
+        None => (spank.job_uid()?, spank.job_gid()?),
+    };
+    let constraints = &plugin.skybox_config.constraints;
+    if let Err(e) = edf_constraints_check(constraints, &edf, uid, gid) {
+        skybox_log_error!("{}", e);
+        return Err(e);
+    }
+    edf_constraints_apply_readonly(constraints, &mut edf, uid, gid);
+
     update_config_by_user(&mut job_config, edf.clone())?;
     remove_sarus_annotations(&mut edf)?;
     plugin.edf = Some(edf);
@@ -175,48 +659,134 @@ pub(crate) fn render_user_job_config(
     Ok(())
 }
 
-pub(crate) fn log_hook_ec(ec: ExecutedCommand, prefix: &str) {
-
-    let rc = match ec.output.status.code() {
-        Some(ok) => format!("{ok}"),
-        None => {
-            skybox_log_debug!("{prefix} exited by signal");
-            String::from("UNKNOWN")
-        }
+/// Merge the partition, account and user layers over the rendered system
+/// configuration, remembering where each key comes from.
+fn config_apply_layers(
+    plugin: &mut SpankSkyBox,
+    spank: &mut SpankHandle,
+    config: Config,
+) -> Result<Config, Box<dyn Error>> {
+    let mut table = match toml::Value::try_from(&config)? {
+        toml::Value::Table(t) => t,
+        _ => return Err("configuration is not a table".into()),
     };
 
-    let mut stdout = match String::from_utf8(ec.output.stdout) {
-        Ok(ok) => ok,
-        Err(_) => String::from(""),
-    };
-    if stdout.ends_with("\n") {
-        stdout.pop();
-    };
+    let mut sources = BTreeMap::new();
+    for key in table.keys() {
+        sources.insert(key.clone(), String::from("system"));
+    }
+
+    let layers = plugin.skybox_config.layers.clone();
 
-    let mut stderr = match String::from_utf8(ec.output.stderr) {
-        Ok(ok) => ok,
-        Err(_) => String::from(""),
+    // Layers are picked with the values of slurmctld, not the job environment.
+    let info = match layers.partition.is_empty() && layers.account.is_empty() {
+        true => JobInfo::default(),
+        false => job_info(plugin, spank)?,
     };
-    if stderr.ends_with("\n") {
-        stderr.pop();
+
+    let partition = info.partition;
+    if let Some(layer) = layers.partition.get(&partition) {
+        let source = format!("partition {partition}");
+        config_merge_layer(&mut table, &mut sources, layer, &source, None);
+    }
+
+    let account = info.account;
+    if let Some(layer) = layers.account.get(&account) {
+        let source = format!("account {account}");
+        config_merge_layer(&mut table, &mut sources, layer, &source, None);
+    }
+
+    // HOME comes from the job environment, the user could point it anywhere.
+    let home = user_home(spank.job_uid()?).unwrap_or_default();
+    if !layers.user_keys.is_empty() && !home.is_empty() && !layers.user_file.is_empty() {
+        let path = format!("{}/{}", home, layers.user_file);
+        if let Some(layer) = config_read_user_layer(spank, &path)? {
+            let keys = Some(&layers.user_keys[..]);
+            config_merge_layer(&mut table, &mut sources, &layer, &path, keys);
+        }
+    }
+
+    plugin.config_sources = sources;
+
+    Ok(toml::Value::Table(table).try_into()?)
+}
+
+// Expand the job variables in the merged configuration, with the rules of
+// the system file.
+fn config_expand(
+    config: &Config,
+    env: &Option<HashMap<String, String>>,
+) -> Result<Config, Box<dyn Error>> {
+    let content = toml::to_string(config)?;
+    let file = mktemp::Temp::new_file()?;
+    std::fs::write(file.as_path(), content)?;
+
+    load_config_path(Some(file.as_path().to_path_buf()), VarExpand::Must, env)
+}
+
+// The user file is read as the job user, a missing file is not an error.
+fn config_read_user_layer(
+    spank: &mut SpankHandle,
+    path: &str,
+) -> Result<Option<toml::Table>, Box<dyn Error>> {
+    let old_uid = setfsuid(Uid::from(spank.job_uid()?));
+    let old_gid = setfsgid(Gid::from(spank.job_gid()?));
+    let content = std::fs::read_to_string(path);
+    let _ = setfsgid(old_gid);
+    let _ = setfsuid(old_uid);
+
+    let content = match content {
+        Ok(c) => c,
+        Err(_) => return Ok(None),
     };
 
-    skybox_log_debug!("CMD: {}", ec.command);
-    skybox_log_debug!("{prefix} exit code: {}", rc);
+    match toml::from_str(&content) {
+        Ok(t) => Ok(Some(t)),
+        Err(e) => Err(format!("cannot parse {path}: {e}").into()),
+    }
+}
 
-    if stdout != "" {
-        let lines = stdout.split("\n");
-        for line in lines {
-            skybox_log_debug!("{prefix} stdout: {}", line);
-            spank_log_user!("{}", line);
+fn config_merge_layer(
+    table: &mut toml::Table,
+    sources: &mut BTreeMap<String, String>,
+    layer: &toml::Table,
+    source: &str,
+    allowed: Option<&[String]>,
+) {
+    for (key, value) in layer.iter() {
+        if key == "skybox" {
+            continue;
         }
+        if !table.contains_key(key) {
+            skybox_log_error!("ignoring unknown configuration key {key} from {source}");
+            continue;
+        }
+        if let Some(keys) = allowed && !keys.contains(key) {
+            skybox_log_user!("WARNING: ignoring {key} from {source}, not allowed by the site");
+            continue;
+        }
+        table.insert(key.clone(), value.clone());
+        sources.insert(key.clone(), String::from(source));
     }
+}
+
+/// Log the effective configuration and the layer each key comes from. Keys
+/// overridden by a layer or plugstack.conf also go to the slurmd log.
+pub(crate) fn config_explain(plugin: &SpankSkyBox) {
+    let table = match toml::Value::try_from(&plugin.config) {
+        Ok(toml::Value::Table(t)) => t,
+        _ => return,
+    };
 
-    if stderr != "" {
-        let lines = stderr.split("\n");
-        for line in lines {
-            skybox_log_debug!("{prefix} stderr: {}", line);
-            spank_log_error!("{}", line);
+    for (key, value) in table.iter() {
+        let source = match plugin.config_sources.get(key) {
+            Some(s) => s.as_str(),
+            None => "system",
+        };
+        if source == "system" {
+            skybox_log_debug!("config {key} = {value} ({source})");
+        } else {
+            skybox_log_info!("config {key} = {value} ({source})");
         }
     }
 }
@@ -0,0 +1,291 @@
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use nix::unistd::{Gid, Uid, setfsgid, setfsuid};
+use raster::mount::SarusMount;
+
+use crate::config::EdfConstraints;
+use crate::edf::{default_mount_sources, edf_mount_fields};
+use crate::redact::glob_match;
+use crate::{plugin_string, skybox_log_debug};
+
+/// An EDF rejected by the site. Unlike configuration errors, which disable
+/// skybox, it fails the step.
+#[derive(Debug)]
+pub(crate) struct ConstraintViolation(String);
+
+impl fmt::Display for ConstraintViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for ConstraintViolation {}
+
+/// Check a user EDF against the site constraints, reporting every violation.
+/// Mount sources are resolved with the credentials of the job user, the
+/// default mounts of skybox itself are not limited to the source prefixes.
+pub(crate) fn edf_constraints_check(
+    constraints: &EdfConstraints,
+    edf: &raster::EDF,
+    uid: u32,
+    gid: u32,
+) -> Result<(), Box<dyn Error>> {
+    let mut violations = vec![];
+    let exempt = default_mount_sources(uid);
+
+    if !constraints.image_registries.is_empty() {
+        let registry = image_registry(&edf.image);
+        if !constraints.image_registries.contains(&registry) {
+            violations.push(format!(
+                "image \"{}\": registry {registry} is not allowed, use one of {}",
+                edf.image,
+                constraints.image_registries.join(", ")
+            ));
+        }
+    }
+
+    for mount in edf.mounts.iter() {
+        let (source, destination, flags) = match edf_mount_fields(mount) {
+            Some(f) => f,
+            None => {
+                violations.push(String::from("mounts: cannot read a mount definition"));
+                continue;
+            }
+        };
+
+        let skybox_mount = source == destination && exempt.contains(&source);
+        if !skybox_mount
+            && let Some(v) =
+                mount_source_violation(&constraints.mount_source_prefixes, &source, uid, gid)
+        {
+            violations.push(format!("mounts: {v} (mount of {destination})"));
+        }
+
+        for flag in flags.iter() {
+            let name = flag.split('=').next().unwrap_or(flag);
+            if constraints.forbidden_mount_flags.iter().any(|f| f == name) {
+                violations.push(format!(
+                    "mounts: flag {name} of {destination} is not allowed"
+                ));
+            }
+        }
+    }
+
+    if let Ok(serde_json::Value::Object(annotations)) = serde_json::to_value(&edf.annotations) {
+        for key in annotations.keys() {
+            let allowed = constraints.annotations_allow.is_empty()
+                || constraints
+                    .annotations_allow
+                    .iter()
+                    .any(|g| glob_match(g, key));
+            let denied = constraints
+                .annotations_deny
+                .iter()
+                .any(|g| glob_match(g, key));
+
+            if !allowed || denied {
+                violations.push(format!("annotations: {key} is not allowed"));
+            }
+        }
+    }
+
+    if violations.is_empty() {
+        return Ok(());
+    }
+
+    let mut msg = String::from("the EDF does not respect the site constraints:");
+    for v in violations.iter() {
+        msg.push_str(&format!("\n  {v}"));
+    }
+    Err(Box::new(ConstraintViolation(plugin_string(&msg))))
+}
+
+/// Make mounts of the configured host paths read-only, except the default
+/// mounts of skybox itself.
+pub(crate) fn edf_constraints_apply_readonly(
+    constraints: &EdfConstraints,
+    edf: &mut raster::EDF,
+    uid: u32,
+    gid: u32,
+) {
+    if constraints.readonly_mount_prefixes.is_empty() {
+        return;
+    }
+
+    let exempt = default_mount_sources(uid);
+
+    for mount in edf.mounts.iter_mut() {
+        let (source, destination, flags) = match edf_mount_fields(mount) {
+            Some(f) => f,
+            None => continue,
+        };
+        if source == destination && exempt.contains(&source) {
+            continue;
+        }
+
+        // A symlink must not lead out of a read-only prefix either.
+        let resolved = mount_source_resolve(&source, uid, gid).unwrap_or(PathBuf::from(&source));
+        if !(path_has_prefix(Path::new(&source), &constraints.readonly_mount_prefixes)
+            || path_has_prefix(&resolved, &constraints.readonly_mount_prefixes))
+            || flags.iter().any(|f| f == "ro")
+        {
+            continue;
+        }
+
+        let mut flags: Vec<String> = flags.into_iter().filter(|f| f != "rw").collect();
+        flags.push(String::from("ro"));
+
+        let mount_string = format!("{}:{}:{}", source, destination, flags.join(","));
+        match SarusMount::try_new(mount_string, &None) {
+            Ok(sm) => {
+                skybox_log_debug!("forcing read-only mount of {source}");
+                *mount = sm;
+            }
+            Err(e) => {
+                skybox_log_debug!("cannot make mount of {source} read-only: {e}");
+            }
+        }
+    }
+}
+
+/// Resolve `..` and symlinks of a mount source as the job user, so that only
+/// what the user can reach is followed.
+pub(crate) fn mount_source_resolve(source: &str, uid: u32, gid: u32) -> std::io::Result<PathBuf> {
+    let old_uid = setfsuid(Uid::from(uid));
+    let old_gid = setfsgid(Gid::from(gid));
+    let result = std::fs::canonicalize(source);
+    let _ = setfsgid(old_gid);
+    let _ = setfsuid(old_uid);
+    result
+}
+
+/// Why a mount source is refused by the `mount_source_prefixes` allowlist,
+/// None when it is accepted or there is no allowlist.
+pub(crate) fn mount_source_violation(
+    prefixes: &[String],
+    source: &str,
+    uid: u32,
+    gid: u32,
+) -> Option<String> {
+    if prefixes.is_empty() {
+        return None;
+    }
+
+    let resolved = match mount_source_resolve(source, uid, gid) {
+        Ok(p) => p,
+        Err(e) => return Some(format!("source {source} cannot be resolved: {e}")),
+    };
+
+    if path_has_prefix(&resolved, prefixes) {
+        return None;
+    }
+
+    Some(format!(
+        "source {source} (resolved to {}) is not under {}",
+        resolved.display(),
+        prefixes.join(", ")
+    ))
+}
+
+// Prefixes may be symlinks themselves, e.g. /scratch -> /capstor/scratch.
+fn path_has_prefix(path: &Path, prefixes: &[String]) -> bool {
+    prefixes.iter().any(|p| {
+        path.starts_with(p)
+            || std::fs::canonicalize(p)
+                .map(|c| path.starts_with(c))
+                .unwrap_or(false)
+    })
+}
+
+// "ubuntu:24.04" comes from docker.io, the first component is a registry when
+// it looks like a host name.
+fn image_registry(image: &str) -> String {
+    match image.split_once('/') {
+        Some((first, _)) if first.contains('.') || first.contains(':') || first == "localhost" => {
+            String::from(first)
+        }
+        _ => String::from("docker.io"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestDir;
+    use nix::unistd::{getgid, getuid};
+    use std::os::unix::fs::symlink;
+
+    fn test_dir(name: &str) -> TestDir {
+        let dir = TestDir::new(&format!("constraints-{name}"));
+        dir.mkdir("allowed/data");
+        dir.mkdir("outside");
+        dir
+    }
+
+    fn violation(dir: &TestDir, source: &str) -> Option<String> {
+        let prefixes = vec![dir.path("allowed")];
+        mount_source_violation(
+            &prefixes,
+            &dir.path(source),
+            getuid().as_raw(),
+            getgid().as_raw(),
+        )
+    }
+
+    #[test]
+    fn source_under_prefix() {
+        let dir = test_dir("under");
+        assert_eq!(violation(&dir, "allowed"), None);
+        assert_eq!(violation(&dir, "allowed/data"), None);
+        assert_eq!(violation(&dir, "allowed/data/../data"), None);
+    }
+
+    #[test]
+    fn source_dotdot_escape() {
+        let dir = test_dir("dotdot");
+        assert!(violation(&dir, "allowed/../outside").is_some());
+        assert!(violation(&dir, "allowed/data/../../outside").is_some());
+    }
+
+    #[test]
+    fn source_symlink_escape() {
+        let dir = test_dir("symlink");
+        symlink(dir.path("outside"), dir.path("allowed/link")).unwrap();
+        symlink("/etc", dir.path("allowed/etc")).unwrap();
+        assert!(violation(&dir, "allowed/link").is_some());
+        assert!(violation(&dir, "allowed/etc").is_some());
+    }
+
+    #[test]
+    fn source_symlink_inside() {
+        let dir = test_dir("inside");
+        symlink(dir.path("allowed/data"), dir.path("allowed/alias")).unwrap();
+        assert_eq!(violation(&dir, "allowed/alias"), None);
+    }
+
+    #[test]
+    fn source_unresolved() {
+        let dir = test_dir("missing");
+        assert!(violation(&dir, "allowed/missing").is_some());
+        symlink(dir.path("allowed/nowhere"), dir.path("allowed/dangling")).unwrap();
+        assert!(violation(&dir, "allowed/dangling").is_some());
+    }
+
+    #[test]
+    fn no_prefixes() {
+        let dir = test_dir("none");
+        let source = dir.path("allowed/../outside");
+        assert_eq!(mount_source_violation(&[], &source, 0, 0), None);
+    }
+
+    #[test]
+    fn registries() {
+        assert_eq!(image_registry("ubuntu:24.04"), "docker.io");
+        assert_eq!(image_registry("library/ubuntu"), "docker.io");
+        assert_eq!(image_registry("quay.io/centos/centos"), "quay.io");
+        assert_eq!(image_registry("localhost:5000/img"), "localhost:5000");
+        assert_eq!(image_registry("localhost/img"), "localhost");
+    }
+}
@@ -3,8 +3,10 @@ use std::env::set_current_dir;
 use std::error::Error;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
+use std::os::unix::fs::MetadataExt;
 //use std::io::Write;
 use cfg_if;
+use nix::unistd::Pid;
 use std::path::Path;
 
 use slurm_spank::{
@@ -15,15 +17,22 @@ use slurm_spank::{
     //spank_log_user,
 };
 
+use crate::config::WorkdirSource;
 use crate::{
+    SKYBOX_CONTAINER_ENV,
+    SKYBOX_EDF_ENV,
+    SLURM_BATCH_SCRIPT,
     SpankSkyBox,
     //create_folder,
     get_local_task_id,
+    parse_env_entry,
     //is_local_task_0,
     plugin_err,
-    //plugin_string,
+    plugin_string,
     skybox_log_debug,
     skybox_log_error,
+    skybox_log_user,
+    spank_getenv,
 };
 
 cfg_if::cfg_if! {
@@ -34,11 +43,19 @@ cfg_if::cfg_if! {
     }
 }
 
+// Set by run_set_info and the start sync, none if the pidfile wasn't usable.
+fn container_pid(ssb: &SpankSkyBox) -> Result<Pid, Box<dyn Error>> {
+    match ssb.run.as_ref().and_then(|r| r.pid) {
+        Some(pid) => Ok(pid),
+        None => Err(plugin_string("couldn't find the container process").into()),
+    }
+}
+
 pub(crate) fn container_join(
     ssb: &mut SpankSkyBox,
     _spank: &mut SpankHandle,
 ) -> Result<(), Box<dyn Error>> {
-    let pid = ssb.run.clone().unwrap().pid;
+    let pid = container_pid(ssb)?;
 
     unsafe {
         // First collect file descriptors for relevant namespaces
@@ -91,7 +108,7 @@ pub(crate) fn container_wait_cwd(
     ssb: &mut SpankSkyBox,
     _spank: &mut SpankHandle,
 ) -> Result<(), Box<dyn Error>> {
-    let pid = ssb.run.clone().unwrap().pid;
+    let pid = container_pid(ssb)?;
     let cwd = format!("/proc/{pid}/cwd");
 
     let mut attempts: u32 = 0;
@@ -147,24 +164,125 @@ so the host cannot access /proc/<pid>/cwd for the container process."
 
 pub(crate) fn container_set_workdir(
     ssb: &mut SpankSkyBox,
-    _spank: &mut SpankHandle,
+    spank: &mut SpankHandle,
+) -> Result<(), Box<dyn Error>> {
+    let edf_workdir = ssb.edf.clone().unwrap().workdir;
+    let pid = container_pid(ssb)?;
+    let host_cwd = ssb.job.clone().unwrap().cwd;
+    let home = spank_getenv(spank, "HOME");
+    let task_id = get_local_task_id(ssb);
+
+    let mut first_choice: Option<String> = None;
+
+    for source in ssb.skybox_config.workdir_fallback.iter() {
+        let candidate = match source {
+            WorkdirSource::Edf => edf_workdir.clone(),
+            WorkdirSource::Pwd => host_cwd.clone(),
+            WorkdirSource::Image => format!("/proc/{pid}/cwd"),
+            WorkdirSource::Home => home.clone(),
+            WorkdirSource::Root => String::from("/"),
+        };
+
+        if candidate.is_empty() {
+            continue;
+        }
+
+        skybox_log_debug!("task {task_id} - changing workdir to {candidate}");
+
+        match set_current_dir(Path::new(&candidate)) {
+            Ok(_) => {
+                if let Some(wanted) = first_choice
+                    && task_id == 0
+                {
+                    skybox_log_user!(
+                        "WARNING: cannot use \"{wanted}\" as working directory inside the container, using \"{}\" instead",
+                        workdir_display(*source, &candidate)
+                    );
+                }
+                return Ok(());
+            }
+            Err(e) => {
+                skybox_log_debug!("task {task_id} - cannot change workdir to {candidate}: {e}");
+                if first_choice.is_none() {
+                    first_choice = Some(workdir_display(*source, &candidate));
+                }
+            }
+        }
+    }
+
+    let msg = match first_choice {
+        Some(wanted) => format!("cannot find a usable working directory, tried \"{wanted}\" first"),
+        None => String::from("cannot find a usable working directory, workdir_fallback is empty"),
+    };
+    skybox_log_error!("task {task_id} - {msg}");
+    plugin_err(&msg)
+}
+
+fn workdir_display(source: WorkdirSource, path: &str) -> String {
+    match source {
+        WorkdirSource::Image => String::from("image WorkingDir"),
+        _ => String::from(path),
+    }
+}
+
+// The batch script is executed after the task joined the container, so its
+// interpreter must exist in the image rather than on the host.
+pub(crate) fn container_check_batch_interpreter(
+    ssb: &mut SpankSkyBox,
+    spank: &mut SpankHandle,
 ) -> Result<(), Box<dyn Error>> {
-    let mut new_workdir = ssb.edf.clone().unwrap().workdir;
+    let job = ssb.job.clone().unwrap();
+    if job.stepid != SLURM_BATCH_SCRIPT {
+        return Ok(());
+    }
 
-    if new_workdir == "" {
-        let pid = ssb.run.clone().unwrap().pid;
-        new_workdir = format!("/proc/{pid}/cwd");
+    let argv = spank.job_argv()?;
+    let script = match argv.first() {
+        Some(s) => s.to_string(),
+        None => return plugin_err("cannot read batch script path"),
+    };
+
+    let interpreter = match batch_script_interpreter(&script) {
+        Ok(Some(i)) => i,
+        Ok(None) => return Ok(()),
+        Err(e) => {
+            let msg = format!("cannot read batch script {script} inside the container: {e}");
+            skybox_log_error!("{msg}");
+            return plugin_err(&msg);
+        }
+    };
+
+    let executable = is_executable_file(&interpreter);
+    if !executable {
+        let msg = format!(
+            "batch script interpreter \"{interpreter}\" not found in the container image \"{}\"",
+            ssb.edf.clone().unwrap().image
+        );
+        skybox_log_user!("{msg}");
+        return plugin_err(&msg);
     }
 
-    //let msg = plugin_string(format!("changing to {new_workdir}").as_str());
-    //spank_log_debug!("{msg}");
-    skybox_log_debug!(
-        "task {} - changing workdir to {new_workdir}",
-        get_local_task_id(ssb)
-    );
+    skybox_log_debug!("batch script interpreter {interpreter} found in the container");
+    Ok(())
+}
+
+// The interpreter named by the shebang of a script, if it has one.
+fn batch_script_interpreter(script: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let mut line = String::new();
+    BufReader::new(File::open(script)?).read_line(&mut line)?;
+
+    let interpreter = line
+        .strip_prefix("#!")
+        .and_then(|rest| rest.split_whitespace().next())
+        .map(String::from);
+    Ok(interpreter)
+}
 
-    let new_cwd = Path::new(&new_workdir);
-    Ok(set_current_dir(&new_cwd)?)
+fn is_executable_file(path: &str) -> bool {
+    match std::fs::metadata(path) {
+        Ok(m) => m.is_file() && m.mode() & 0o111 != 0,
+        Err(_) => false,
+    }
 }
 
 pub(crate) fn container_import_env(
@@ -188,7 +306,7 @@ pub(crate) fn container_import_env(
         }
     }
 
-    let pid = ssb.run.clone().unwrap().pid;
+    let pid = container_pid(ssb)?;
     let environ_path = format!("/proc/{pid}/environ");
     let environ = Path::new(&environ_path);
 
@@ -206,14 +324,19 @@ pub(crate) fn container_import_env(
     let lines = BufReader::new(environ_file).split(0);
     // Consumes the iterator, returns an (Optional) String
     for line in lines.map_while(Result::ok) {
-        let string = String::from_utf8(line)?;
-        match string.split_once('=') {
+        if line.is_empty() {
+            continue;
+        }
+        match parse_env_entry(&line) {
             Some((key, value)) => {
                 //spank_log_user!("{} = {}", key, value);
-                container_vars.insert(String::from(key), String::from(value));
+                container_vars.insert(key, value);
             }
             None => {
-                let msg = format!("couldn't parse environ value {string}");
+                let msg = format!(
+                    "couldn't parse environ value {}",
+                    String::from_utf8_lossy(&line)
+                );
                 //spank_log_error!("{msg}");
                 skybox_log_error!("{msg}");
                 return Err(format!("[{}] {}", crate::get_plugin_name(), msg).into());
@@ -262,6 +385,13 @@ pub(crate) fn container_import_env(
         }
     }
 
+    // Mark the tasks, so nested skybox steps know they are launched from a container.
+    let name = ssb.run.clone().unwrap().name;
+    spank.setenv(SKYBOX_CONTAINER_ENV, &name, true)?;
+    if let Some(edf_name) = &ssb.args.edf {
+        spank.setenv(SKYBOX_EDF_ENV, edf_name, true)?;
+    }
+
     Ok(())
 }
 /*
@@ -330,3 +460,43 @@ pub(crate) fn container_join_wait(
     return Ok(());
 }
 */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestDir;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn batch_interpreter_from_shebang() {
+        let dir = TestDir::new("container-interpreter");
+        let script = dir.path("script");
+
+        std::fs::write(&script, "#!/bin/bash -l\necho hi\n").unwrap();
+        assert_eq!(
+            batch_script_interpreter(&script).unwrap().as_deref(),
+            Some("/bin/bash")
+        );
+
+        std::fs::write(&script, "#! /usr/bin/env python3\n").unwrap();
+        assert_eq!(
+            batch_script_interpreter(&script).unwrap().as_deref(),
+            Some("/usr/bin/env")
+        );
+
+        std::fs::write(&script, "echo no shebang\n").unwrap();
+        assert_eq!(batch_script_interpreter(&script).unwrap(), None);
+
+        std::fs::write(&script, "#!\n").unwrap();
+        assert_eq!(batch_script_interpreter(&script).unwrap(), None);
+
+        assert!(batch_script_interpreter(&format!("{script}.missing")).is_err());
+
+        // Only regular files with an execute bit count as an interpreter.
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(!is_executable_file(&script));
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(is_executable_file(&script));
+        assert!(!is_executable_file(&dir.path("")));
+    }
+}
@@ -4,12 +4,15 @@ use slurm_spank::{Context, Plugin, SpankHandle};
 
 use crate::SpankSkyBox;
 use crate::alloc::*;
+use crate::logging::log_set_phase;
 use crate::slurmd::*;
 use crate::slurmstepd::*;
 use crate::srun::*;
 
 unsafe impl Plugin for SpankSkyBox {
     fn init(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+        log_set_phase("init");
+
         match spank.context()? {
             Context::Slurmd => {
                 let _ = slurmd_init(self, spank)?;
@@ -30,6 +33,8 @@ unsafe impl Plugin for SpankSkyBox {
     }
 
     fn init_post_opt(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+        log_set_phase("init_post_opt");
+
         if !self.config.skybox_enabled {
             return Ok(());
         }
@@ -51,6 +56,8 @@ unsafe impl Plugin for SpankSkyBox {
     }
 
     fn user_init(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+        log_set_phase("user_init");
+
         if !self.config.skybox_enabled {
             return Ok(());
         }
@@ -59,6 +66,8 @@ unsafe impl Plugin for SpankSkyBox {
     }
 
     fn task_init(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+        log_set_phase("task_init");
+
         if !self.config.skybox_enabled {
             return Ok(());
         }
@@ -67,6 +76,8 @@ unsafe impl Plugin for SpankSkyBox {
     }
 
     fn exit(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+        log_set_phase("exit");
+
         if !self.config.skybox_enabled {
             return Ok(());
         }
@@ -90,7 +101,15 @@ unsafe impl Plugin for SpankSkyBox {
         Ok(())
     }
 
+    fn job_epilog(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+        log_set_phase("job_epilog");
+
+        slurmd_job_epilog(self, spank)
+    }
+
     fn slurmd_exit(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+        log_set_phase("slurmd_exit");
+
         if !self.config.skybox_enabled {
             return Ok(());
         }
@@ -99,6 +118,8 @@ unsafe impl Plugin for SpankSkyBox {
     }
 
     fn task_exit(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+        log_set_phase("task_exit");
+
         if !self.config.skybox_enabled {
             return Ok(());
         }
@@ -107,6 +128,8 @@ unsafe impl Plugin for SpankSkyBox {
     }
 
     fn task_init_privileged(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+        log_set_phase("task_init_privileged");
+
         if !self.config.skybox_enabled {
             return Ok(());
         }
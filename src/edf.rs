@@ -1,10 +1,28 @@
+use nix::unistd::{Gid, Uid, getgid, getuid, setfsgid, setfsuid};
 use slurm_spank::{Context, SpankHandle};
 
+use std::collections::HashMap;
 use std::error::Error;
+use std::path::Path;
 
 use raster::mount::SarusMount;
+use users::os::unix::UserExt;
 
-use crate::{SLURM_BATCH_SCRIPT, SpankSkyBox, skybox_log_debug, spank_getenv};
+use crate::admission::submit_partition;
+use crate::config::{DefaultMounts, EdfConstraints, EdfSource};
+use crate::constraints::mount_source_violation;
+
+use crate::{
+    SKYBOX_CONTAINER_ENV, SKYBOX_EDF_DEFAULT_ENV, SKYBOX_EDF_ENV, SLURM_BATCH_SCRIPT, SpankSkyBox,
+    get_job_env, plugin_err, plugin_string, skybox_log_debug, skybox_log_error, skybox_log_info,
+    skybox_log_user, spank_getenv,
+};
+
+// Linux limit for a single environment string (MAX_ARG_STRLEN).
+const EDF_ENV_MAX_BYTES: usize = 128 * 1024;
+const DEFAULT_MOUNT_FLAGS: &str = "bind,nosuid,nodev,private";
+const DEFAULT_MOUNT_RO_FLAGS: &str = "bind,ro,nosuid,nodev,private";
+const SLURM_CLIENT_PATHS: [&str; 2] = ["/etc/slurm", "/var/run/munge"];
 
 pub(crate) fn load_edf(
     ssb: &mut SpankSkyBox,
@@ -18,15 +36,28 @@ pub(crate) fn load_edf(
     };
 
     let edf: raster::EDF;
+    let defaults = mount_defaults(ssb, spank)?;
 
     match spank.context()? {
         Context::Local | Context::Allocator => {
-            skybox_log_debug!("load_edf taking local_edf_render branch");
-            edf = local_edf_render(edf_name)?;
+            edf = match reentry_get_edf(&edf_name) {
+                Some(e) => e,
+                None => {
+                    skybox_log_debug!("load_edf taking local_edf_render branch");
+                    let remote_render = ssb.skybox_config.edf_source == EdfSource::Render;
+                    local_edf_render(edf_name, remote_render, &defaults)?
+                }
+            };
         }
         Context::Remote => {
-            skybox_log_debug!("load_edf taking spank_remote_get_edf branch");
-            edf = spank_remote_get_edf(spank)?;
+            // The submit side had no default EDF, run the step without container.
+            if ssb.args.edf_default.is_some()
+                && spank_getenv(spank, "SLURM_EDF_EXPANDED").is_empty()
+            {
+                skybox_log_debug!("load_edf: no EDF exported for default EDF {edf_name}");
+                return Ok(());
+            }
+            edf = spank_remote_load_edf(ssb, spank, &edf_name, &defaults)?;
         }
         _ => {
             skybox_log_debug!("load_edf: unsupported context, returning early");
@@ -41,37 +72,152 @@ pub(crate) fn load_edf(
     Ok(())
 }
 
-fn local_edf_render(path: String) -> Result<raster::EDF, Box<dyn Error>> {
+// A step launched from inside a skybox container with the same EDF reuses the
+// parent rendering, the EDF search paths may not be visible in the container.
+fn reentry_get_edf(edf_name: &str) -> Option<raster::EDF> {
+    let container = std::env::var(SKYBOX_CONTAINER_ENV).ok()?;
+    let parent_edf = std::env::var(SKYBOX_EDF_ENV).ok()?;
+
+    if parent_edf != edf_name {
+        return None;
+    }
+
+    let expanded = std::env::var("SLURM_EDF_EXPANDED").ok()?;
+    match raster::get_edf_from_string(expanded) {
+        Ok(edf) => {
+            skybox_log_debug!("load_edf reusing EDF of parent container {container}");
+            Some(edf)
+        }
+        Err(e) => {
+            skybox_log_debug!("cannot reuse EDF of parent container {container}: {e}");
+            None
+        }
+    }
+}
+
+/// Name and origin of the default EDF of a job without --edf. The nodes use
+/// the one picked at submission.
+pub(crate) fn edf_default(ssb: &SpankSkyBox, spank: &mut SpankHandle) -> Option<(String, String)> {
+    if let Ok(Context::Remote) = spank.context() {
+        let name = spank_getenv(spank, SKYBOX_EDF_DEFAULT_ENV);
+        if name.is_empty() {
+            return None;
+        }
+        return Some((name, String::from("submission")));
+    }
+
+    let defaults = &ssb.skybox_config.default_edf;
+
+    let home = spank_getenv(spank, "HOME");
+    if defaults.user && !home.is_empty() {
+        let path = format!("{home}/.edf/default.toml");
+        if Path::new(&path).exists() {
+            return Some((path, String::from("user")));
+        }
+    }
+
+    let partition = submit_partition(spank);
+    if let Some(name) = defaults.partitions.get(&partition) {
+        return Some((name.clone(), format!("partition {partition}")));
+    }
+
+    let site = match &ssb.plugstack.default_edf {
+        Some(s) => s.clone(),
+        None => defaults.site.clone(),
+    };
+    if !site.is_empty() {
+        return Some((site, String::from("site")));
+    }
+
+    None
+}
+
+pub(crate) fn define_edf_default_envvar(name: &str) {
+    unsafe {
+        std::env::set_var(SKYBOX_EDF_DEFAULT_ENV, name);
+    }
+}
+
+pub(crate) fn clear_edf_default_envvar() {
+    unsafe {
+        std::env::remove_var(SKYBOX_EDF_DEFAULT_ENV);
+    }
+}
+
+fn local_edf_render(
+    path: String,
+    remote_render: bool,
+    defaults: &MountDefaults,
+) -> Result<raster::EDF, Box<dyn Error>> {
     skybox_log_debug!("local_edf_render requested path='{}'", path);
 
-    let edf = raster::render(path)?;
+    let edf = edf_render(path, std::env::vars().collect(), Some(defaults))?;
 
     skybox_log_debug!("local_edf_render rendered image='{}'", edf.image);
     skybox_log_debug!("local_edf_render annotations={:?}", edf.annotations);
 
-    define_edf_expanded_envvar(&edf)?;
+    define_edf_expanded_envvar(&edf, remote_render)?;
     Ok(edf)
 }
 
-fn define_edf_expanded_envvar(edf: &raster::EDF) -> Result<(), Box<dyn Error>> {
+fn define_edf_expanded_envvar(
+    edf: &raster::EDF,
+    remote_render: bool,
+) -> Result<(), Box<dyn Error>> {
     let key = "SLURM_EDF_EXPANDED";
     let value = edf.to_toml_string()?;
+
+    // A larger variable makes the exec of the tasks fail, the nodes have to
+    // render the EDF themselves.
+    if key.len() + value.len() + 1 > EDF_ENV_MAX_BYTES {
+        if remote_render {
+            skybox_log_debug!("EDF is {} bytes, leaving it to the nodes", value.len());
+            return Ok(());
+        }
+        return plugin_err(&format!(
+            "the EDF is too large to hand over through the environment ({} bytes)",
+            value.len()
+        ));
+    }
+
     unsafe {
         std::env::set_var(key, value);
     }
     Ok(())
 }
 
-/*
 fn spank_remote_edf_render(
     path: String,
     spank: &mut SpankHandle,
+    defaults: &MountDefaults,
 ) -> Result<raster::EDF, Box<dyn Error>> {
-    let sp = spank_remote_get_search_paths(spank);
-    let ue = &Some(get_job_env(spank));
-    Ok(raster::render_from_search_paths(path, sp, ue)?)
+    edf_render(path, get_job_env(spank), Some(defaults))
+}
+
+// Both sides render through here, the submit side with its own environment
+// and the nodes with the job environment, so that they agree. The default
+// mounts are part of the rendering.
+fn edf_render(
+    path: String,
+    env: HashMap<String, String>,
+    defaults: Option<&MountDefaults>,
+) -> Result<raster::EDF, Box<dyn Error>> {
+    let sp = edf_search_paths(&env);
+    edf_render_from(path, sp, env, defaults)
+}
+
+fn edf_render_from(
+    path: String,
+    search_paths: Vec<String>,
+    env: HashMap<String, String>,
+    defaults: Option<&MountDefaults>,
+) -> Result<raster::EDF, Box<dyn Error>> {
+    let mut edf = raster::render_from_search_paths(path, search_paths, &Some(env))?;
+    if let Some(d) = defaults {
+        edf_add_default_mounts(&mut edf, d);
+    }
+    Ok(edf)
 }
-*/
 
 fn spank_remote_get_edf(spank: &mut SpankHandle) -> Result<raster::EDF, Box<dyn Error>> {
     let key = "SLURM_EDF_EXPANDED";
@@ -80,11 +226,177 @@ fn spank_remote_get_edf(spank: &mut SpankHandle) -> Result<raster::EDF, Box<dyn
     Ok(edf)
 }
 
-/*
-fn spank_remote_get_search_paths(spank: &mut SpankHandle) -> Vec<String> {
+// Render on the node as the job user if configured, the environment handoff
+// is the fallback and is verified against a re-render when asked for.
+fn spank_remote_load_edf(
+    ssb: &SpankSkyBox,
+    spank: &mut SpankHandle,
+    name: &str,
+    defaults: &MountDefaults,
+) -> Result<raster::EDF, Box<dyn Error>> {
+    if ssb.skybox_config.edf_source == EdfSource::Render {
+        skybox_log_debug!("load_edf taking spank_remote_edf_render branch");
+        let path = spank_remote_edf_path(name, spank);
+        match spank_remote_edf_render(path, spank, defaults) {
+            Ok(edf) => {
+                // Both paths are expected to agree, note when they don't.
+                if let Ok(handoff) = spank_remote_get_edf(spank)
+                    && serde_json::to_value(&handoff)? != serde_json::to_value(&edf)?
+                {
+                    skybox_log_info!("EDF {name} rendered on this node differs from submission");
+                }
+                return Ok(edf);
+            }
+            Err(e) => {
+                skybox_log_debug!(
+                    "cannot render EDF {name} on this node, using SLURM_EDF_EXPANDED: {e}"
+                );
+            }
+        }
+    }
+
+    skybox_log_debug!("load_edf taking spank_remote_get_edf branch");
+    let edf = spank_remote_get_edf(spank)?;
+    edf_verify_remote(ssb, spank, &edf, name, defaults)?;
+    Ok(edf)
+}
+
+// EDF files given by relative path are relative to the submit side cwd.
+fn spank_remote_edf_path(name: &str, spank: &mut SpankHandle) -> String {
+    edf_remote_path(name, &spank_getenv(spank, "PWD"))
+}
+
+fn edf_remote_path(name: &str, cwd: &str) -> String {
+    if !name.contains('/') || name.starts_with('/') || cwd.is_empty() {
+        return String::from(name);
+    }
+    format!("{cwd}/{name}")
+}
+
+// Re-render the EDF from the job user's search paths and refuse the one
+// handed over in SLURM_EDF_EXPANDED if a field that doesn't depend on the
+// environment differs, so the submit side rendering can't be replaced by hand.
+fn edf_verify_remote(
+    ssb: &SpankSkyBox,
+    spank: &mut SpankHandle,
+    edf: &raster::EDF,
+    name: &str,
+    defaults: &MountDefaults,
+) -> Result<(), Box<dyn Error>> {
+    if !ssb.skybox_config.verify_edf {
+        return Ok(());
+    }
+
+    let path = spank_remote_edf_path(name, spank);
+    let differences = match edf_verify(edf, path, get_job_env(spank), Some(defaults)) {
+        Ok(d) => d,
+        Err(e) => {
+            return plugin_err(&format!("cannot verify EDF {name}, rendering failed: {e}"));
+        }
+    };
+
+    if !differences.is_empty() {
+        skybox_log_error!(
+            "SLURM_EDF_EXPANDED does not match EDF {name} at {}",
+            differences.join(", ")
+        );
+        return plugin_err(&format!(
+            "EDF {name} changed since submission or was tampered with, refusing to run"
+        ));
+    }
+
+    skybox_log_debug!("EDF {name} verified");
+    Ok(())
+}
+
+// Value of every variable in the second rendering of edf_verify, a path so
+// that variables used in mount sources and workdirs still render.
+const EDF_VERIFY_PLACEHOLDER: &str = "/skybox-verify-placeholder";
+
+/// Fields of `handoff` that differ from the EDF rendered from `path`.
+///
+/// Variables differ between the submit side and the nodes (SLURM_STEP_ID,
+/// SLURMD_NODENAME, SLURM_PROCID, anything the job changed), so the EDF is
+/// rendered twice, with the environment and with every variable set to a
+/// placeholder. Only fields equal in both renderings are compared.
+fn edf_verify(
+    handoff: &raster::EDF,
+    path: String,
+    env: HashMap<String, String>,
+    defaults: Option<&MountDefaults>,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let sp = edf_search_paths(&env);
+    let placeholders = env
+        .keys()
+        .map(|k| (k.clone(), String::from(EDF_VERIFY_PLACEHOLDER)))
+        .collect();
+
+    let rendered = edf_render_from(path.clone(), sp.clone(), env, defaults)?;
+    let placeholder = edf_render_from(path, sp, placeholders, defaults)?;
+
+    let mut differences = vec![];
+    json_diff_fixed(
+        &serde_json::to_value(handoff)?,
+        &serde_json::to_value(&rendered)?,
+        &serde_json::to_value(&placeholder)?,
+        "",
+        &mut differences,
+    );
+    Ok(differences)
+}
+
+// Compare `value` with `expected` where `expected` and `other` agree, i.e.
+// where the renderings don't depend on the environment. Maps are compared
+// by key, so their ordering doesn't matter.
+fn json_diff_fixed(
+    value: &serde_json::Value,
+    expected: &serde_json::Value,
+    other: &serde_json::Value,
+    at: &str,
+    differences: &mut Vec<String>,
+) {
+    use serde_json::Value;
+
+    if expected == other {
+        if value != expected {
+            differences.push(String::from(if at.is_empty() { "/" } else { at }));
+        }
+        return;
+    }
+
+    match (value, expected, other) {
+        (Value::Object(v), Value::Object(e), Value::Object(o)) => {
+            for key in v
+                .keys()
+                .filter(|k| !e.contains_key(*k) && !o.contains_key(*k))
+            {
+                differences.push(format!("{at}/{key}"));
+            }
+            for (key, e) in e.iter() {
+                let Some(o) = o.get(key) else { continue };
+                match v.get(key) {
+                    Some(v) => json_diff_fixed(v, e, o, &format!("{at}/{key}"), differences),
+                    None => differences.push(format!("{at}/{key}")),
+                }
+            }
+        }
+        (Value::Array(v), Value::Array(e), Value::Array(o)) if e.len() == o.len() => {
+            if v.len() != e.len() {
+                differences.push(String::from(if at.is_empty() { "/" } else { at }));
+                return;
+            }
+            for (i, ((v, e), o)) in v.iter().zip(e).zip(o).enumerate() {
+                json_diff_fixed(v, e, o, &format!("{at}/{i}"), differences);
+            }
+        }
+        _ => (),
+    }
+}
+
+fn edf_search_paths(env: &HashMap<String, String>) -> Vec<String> {
     let mut search_paths = vec![];
 
-    let user_sp = spank_remote_get_user_search_paths(spank);
+    let user_sp = edf_user_search_paths(env);
     search_paths.extend(user_sp);
 
     let sys_sp = raster::get_sys_search_paths();
@@ -93,14 +405,13 @@ fn spank_remote_get_search_paths(spank: &mut SpankHandle) -> Vec<String> {
     search_paths
 }
 
-
-fn spank_remote_get_user_search_paths(spank: &mut SpankHandle) -> Vec<String> {
+fn edf_user_search_paths(env: &HashMap<String, String>) -> Vec<String> {
     let mut search_paths = vec![];
 
-    let mut edf_path = spank_getenv(spank, "EDF_PATH");
+    let mut edf_path = env.get("EDF_PATH").cloned().unwrap_or_default();
 
     if edf_path == "" {
-        let home_path = spank_getenv(spank, "HOME");
+        let home_path = env.get("HOME").cloned().unwrap_or_default();
 
         if home_path != "" {
             edf_path = format!("{home_path}/.edf");
@@ -114,6 +425,7 @@ fn spank_remote_get_user_search_paths(spank: &mut SpankHandle) -> Vec<String> {
     search_paths
 }
 
+/*
 pub(crate) fn update_edf_defaults_via_config(ssb: &mut SpankSkyBox) -> Result<(), Box<dyn Error>> {
     let mut edf = match ssb.edf.clone() {
         Some(e) => e,
@@ -196,21 +508,516 @@ pub(crate) fn modify_edf_for_sbatch(
             }
         };
 
-        let flags = String::from("bind,ro,nosuid,nodev,private");
-        let mount_string = format!("{}:{}:{}", &sbatch_script, &sbatch_script, &flags);
+        if let Err(e) = edf_add_spool_mount(&mut edf, sbatch_script) {
+            skybox_log_debug!("cannot create sbatch script mount defintion: {e}");
+            return Ok(());
+        }
 
-        let sm = match SarusMount::try_new(mount_string, &None) {
-            Ok(ok) => ok,
-            Err(_) => {
-                skybox_log_debug!("cannot create sbatch script mount defintion");
-                return Ok(());
+        ssb.edf = Some(edf);
+    }
+    Ok(())
+}
+
+// Mount the whole slurmd spool directory of the job, not only the script.
+// The --output and --error files are opened by slurmstepd on the host before
+// the batch step joins the container, so their paths are host paths and need
+// no mount.
+fn edf_add_spool_mount(edf: &mut raster::EDF, script: &str) -> Result<(), Box<dyn Error>> {
+    let spool_dir = match Path::new(script).parent() {
+        Some(p) if p != Path::new("/") && p != Path::new("") => p.to_string_lossy().to_string(),
+        _ => String::from(script),
+    };
+
+    let flags = String::from("bind,ro,nosuid,nodev,private");
+    let mount_string = format!("{}:{}:{}", &spool_dir, &spool_dir, &flags);
+    let sm = match SarusMount::try_new(mount_string, &None) {
+        Ok(sm) => sm,
+        Err(e) => return Err(plugin_string(&format!("{spool_dir}: {e}")).into()),
+    };
+
+    skybox_log_debug!("NEW MOUNT: {}", spool_dir);
+    edf.mounts.push(sm);
+    Ok(())
+}
+
+pub(crate) fn edf_annotation(edf: &raster::EDF, key: &str) -> Option<String> {
+    let annotations = serde_json::to_value(&edf.annotations).ok()?;
+
+    match annotations.get(key)? {
+        serde_json::Value::Null => None,
+        serde_json::Value::String(s) => Some(s.clone()),
+        v => Some(v.to_string()),
+    }
+}
+
+pub(crate) fn edf_annotation_enabled(edf: &raster::EDF, key: &str, default: bool) -> bool {
+    let value = match edf_annotation(edf, key) {
+        Some(v) => v,
+        None => return default,
+    };
+
+    match value.to_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => true,
+        "false" | "no" | "off" | "0" => false,
+        _ => {
+            skybox_log_debug!("ignoring annotation {key}=\"{value}\", not a boolean");
+            default
+        }
+    }
+}
+
+pub(crate) fn edf_mount_destinations(edf: &raster::EDF) -> Vec<String> {
+    edf.mounts
+        .iter()
+        .filter_map(|m| serde_json::to_value(m).ok())
+        .filter_map(|v| v.get("destination")?.as_str().map(String::from))
+        .collect()
+}
+
+/// Source, destination and flags of a mount, as serialized by raster.
+pub(crate) fn edf_mount_fields(mount: &SarusMount) -> Option<(String, String, Vec<String>)> {
+    let v = serde_json::to_value(mount).ok()?;
+    let source = v.get("source")?.as_str()?.to_string();
+    let destination = v.get("destination")?.as_str()?.to_string();
+
+    let flags = match v.get("flags") {
+        Some(serde_json::Value::String(s)) => s
+            .split(',')
+            .filter(|f| !f.is_empty())
+            .map(String::from)
+            .collect(),
+        Some(serde_json::Value::Array(a)) => a
+            .iter()
+            .filter_map(|f| f.as_str().map(String::from))
+            .collect(),
+        Some(serde_json::Value::Object(o)) => o
+            .iter()
+            .filter_map(|(k, f)| match f {
+                serde_json::Value::Bool(false) | serde_json::Value::Null => None,
+                serde_json::Value::String(s) => Some(format!("{k}={s}")),
+                _ => Some(k.clone()),
+            })
+            .collect(),
+        _ => vec![],
+    };
+
+    Some((source, destination, flags))
+}
+
+/// What the default mounts are made of on this side of the job: the submit
+/// environment on srun, sbatch and salloc, the job on the nodes.
+pub(crate) struct MountDefaults {
+    pub(crate) policy: DefaultMounts,
+    pub(crate) constraints: EdfConstraints,
+    pub(crate) mount_home: Option<bool>,
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+    pub(crate) submit_dir: String,
+    pub(crate) scratch: String,
+}
+
+pub(crate) fn mount_defaults(
+    ssb: &SpankSkyBox,
+    spank: &mut SpankHandle,
+) -> Result<MountDefaults, Box<dyn Error>> {
+    let policy = ssb.skybox_config.default_mounts.clone();
+
+    let (uid, gid, submit_dir, scratch) = match spank.context()? {
+        Context::Remote => {
+            let mut submit_dir = spank_getenv(spank, "SLURM_SUBMIT_DIR");
+            if submit_dir.is_empty() {
+                submit_dir = spank_getenv(spank, "PWD");
             }
-        };
+            let scratch = match policy.scratch_env.as_str() {
+                "" => String::new(),
+                v => spank_getenv(spank, v),
+            };
+            (spank.job_uid()?, spank.job_gid()?, submit_dir, scratch)
+        }
+        _ => {
+            let submit_dir = match std::env::var("SLURM_SUBMIT_DIR") {
+                Ok(d) if !d.is_empty() => d,
+                _ => std::env::current_dir()?.to_string_lossy().to_string(),
+            };
+            let scratch = match policy.scratch_env.as_str() {
+                "" => String::new(),
+                v => std::env::var(v).unwrap_or_default(),
+            };
+            (getuid().as_raw(), getgid().as_raw(), submit_dir, scratch)
+        }
+    };
 
-        skybox_log_debug!("NEW MOUNT: {}", sbatch_script);
-        edf.mounts.append(&mut vec![sm]);
+    Ok(MountDefaults {
+        policy,
+        constraints: ssb.skybox_config.constraints.clone(),
+        mount_home: ssb.args.mount_home,
+        uid,
+        gid,
+        submit_dir,
+        scratch,
+    })
+}
 
-        ssb.edf = Some(edf);
+fn edf_add_default_mounts(edf: &mut raster::EDF, d: &MountDefaults) {
+    if !edf_annotation_enabled(edf, "skybox.mounts.defaults", true) {
+        skybox_log_debug!("default mounts disabled by EDF annotation");
+        return;
+    }
+
+    let policy = &d.policy;
+    let mut wanted: Vec<(String, &str)> = vec![];
+
+    let mount_home = match d.mount_home {
+        Some(v) => v,
+        None => edf_annotation_enabled(edf, "skybox.mounts.home", policy.home),
+    };
+    // The job environment is the user's, take the home directory from passwd.
+    if mount_home {
+        match user_home(d.uid) {
+            Some(home) => wanted.push((home, DEFAULT_MOUNT_FLAGS)),
+            None => skybox_log_debug!("cannot find the home directory of uid {}", d.uid),
+        }
+    }
+
+    if edf_annotation_enabled(edf, "skybox.mounts.submit_dir", policy.submit_dir)
+        && let Some(path) = env_mount_source(&d.constraints, &d.submit_dir, d.uid, d.gid)
+    {
+        wanted.push((path, DEFAULT_MOUNT_FLAGS));
+    }
+
+    if !policy.scratch_env.is_empty()
+        && edf_annotation_enabled(edf, "skybox.mounts.scratch", true)
+        && let Some(path) = env_mount_source(&d.constraints, &d.scratch, d.uid, d.gid)
+    {
+        wanted.push((path, DEFAULT_MOUNT_FLAGS));
     }
+
+    if edf_annotation_enabled(edf, "skybox.mounts.slurm", policy.slurm) {
+        for path in SLURM_CLIENT_PATHS {
+            wanted.push((String::from(path), DEFAULT_MOUNT_RO_FLAGS));
+        }
+    }
+
+    edf_add_host_mounts(edf, wanted, d.uid, d.gid);
+}
+
+pub(crate) fn user_home(uid: u32) -> Option<String> {
+    users::get_user_by_uid(uid).map(|u| u.home_dir().to_string_lossy().to_string())
+}
+
+/// Mounts skybox adds itself and that are not subject to the EDF
+/// constraints: the home directory of the job user and the Slurm client
+/// paths. The submit and scratch directories are checked when added.
+pub(crate) fn default_mount_sources(uid: u32) -> Vec<String> {
+    let mut sources: Vec<String> = SLURM_CLIENT_PATHS
+        .iter()
+        .map(|p| String::from(*p))
+        .collect();
+    if let Some(home) = user_home(uid) {
+        sources.push(home);
+    }
+    sources
+}
+
+// Job steps launched by a containerized batch script need the Slurm client.
+fn slurm_client_enabled(edf: &raster::EDF, stepid: u32) -> bool {
+    edf_annotation_enabled(edf, "skybox.slurm_client", stepid == SLURM_BATCH_SCRIPT)
+}
+
+pub(crate) fn add_slurm_client_mounts(
+    ssb: &mut SpankSkyBox,
+    _spank: &mut SpankHandle,
+) -> Result<(), Box<dyn Error>> {
+    let mut edf = match ssb.edf.clone() {
+        Some(e) => e,
+        None => {
+            return Ok(());
+        }
+    };
+
+    let job = match &ssb.job {
+        Some(j) => j,
+        None => {
+            skybox_log_debug!("cannot find job data at this stage");
+            return Ok(());
+        }
+    };
+
+    if !slurm_client_enabled(&edf, job.stepid) {
+        return Ok(());
+    }
+
+    skybox_log_debug!("injecting host Slurm client into the container");
+
+    let wanted = ssb
+        .skybox_config
+        .slurm_client
+        .paths
+        .iter()
+        .map(|p| (p.clone(), DEFAULT_MOUNT_RO_FLAGS))
+        .collect();
+
+    edf_add_host_mounts(&mut edf, wanted, job.uid, job.gid);
+
+    ssb.edf = Some(edf);
     Ok(())
 }
+
+// Sources taken from the job environment can be set to anything by the user.
+// Keep directories the job user can open, within the site mount prefixes.
+fn env_mount_source(
+    constraints: &EdfConstraints,
+    path: &str,
+    uid: u32,
+    gid: u32,
+) -> Option<String> {
+    if path.is_empty() {
+        return None;
+    }
+
+    if let Some(v) = mount_source_violation(&constraints.mount_source_prefixes, path, uid, gid) {
+        skybox_log_user!("WARNING: not mounting {path}: {v}");
+        return None;
+    }
+
+    let old_uid = setfsuid(Uid::from(uid));
+    let old_gid = setfsgid(Gid::from(gid));
+    let readable = std::fs::read_dir(path).is_ok();
+    let _ = setfsgid(old_gid);
+    let _ = setfsuid(old_uid);
+
+    if !readable {
+        skybox_log_user!("WARNING: not mounting {path}: not a directory the job user can open");
+        return None;
+    }
+
+    Some(String::from(path))
+}
+
+// Bind mount host paths at the same location inside the container, skipping
+// missing sources and destinations the EDF already mounts.
+fn edf_add_host_mounts(edf: &mut raster::EDF, wanted: Vec<(String, &str)>, uid: u32, gid: u32) {
+    let mut destinations = edf_mount_destinations(edf);
+
+    // Check sources with the job user credentials, home and scratch may be root squashed.
+    let old_uid = setfsuid(Uid::from(uid));
+    let old_gid = setfsgid(Gid::from(gid));
+
+    for (path, flags) in wanted {
+        if path.is_empty() || destinations.contains(&path) {
+            continue;
+        }
+
+        if !Path::new(&path).exists() {
+            skybox_log_debug!("host mount {path} not found, skipping");
+            continue;
+        }
+
+        let mount_string = format!("{}:{}:{}", &path, &path, flags);
+        match SarusMount::try_new(mount_string, &None) {
+            Ok(sm) => {
+                skybox_log_debug!("NEW MOUNT: {}", path);
+                edf.mounts.push(sm);
+                destinations.push(path);
+            }
+            Err(e) => {
+                skybox_log_debug!("cannot create mount definition for {path}: {e}");
+            }
+        }
+    }
+
+    let _ = setfsgid(old_gid);
+    let _ = setfsuid(old_uid);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestDir;
+
+    fn test_dir(name: &str) -> TestDir {
+        let dir = TestDir::new(&format!("edf-{name}"));
+        dir.mkdir("edfs");
+        dir.mkdir("work");
+        dir.write(
+            "edfs/app.toml",
+            "image = \"alpine:3.20\"\nworkdir = \"/work\"\n\n[env]\nGREETING = \"hello ${USER}\"\n",
+        );
+        dir.write(
+            "work/local.toml",
+            "base_environment = \"app\"\n\n[annotations]\n\"com.example.note\" = \"local\"\n",
+        );
+        dir
+    }
+
+    fn test_env(dir: &TestDir) -> HashMap<String, String> {
+        let mut env: HashMap<String, String> = std::env::vars().collect();
+        env.insert(String::from("EDF_PATH"), dir.path("edfs"));
+        env.insert(String::from("USER"), String::from("skybox"));
+        env
+    }
+
+    // What the submit side hands over in SLURM_EDF_EXPANDED, as the nodes read it.
+    fn local_handoff(path: &str, env: HashMap<String, String>) -> serde_json::Value {
+        let edf = edf_render(String::from(path), env, None).unwrap();
+        let handoff = raster::get_edf_from_string(edf.to_toml_string().unwrap()).unwrap();
+        serde_json::to_value(&handoff).unwrap()
+    }
+
+    fn remote_render(name: &str, cwd: &str, env: HashMap<String, String>) -> serde_json::Value {
+        let edf = edf_render(edf_remote_path(name, cwd), env, None).unwrap();
+        serde_json::to_value(&edf).unwrap()
+    }
+
+    #[test]
+    fn local_and_remote_agree_by_name() {
+        let dir = test_dir("name");
+        assert_eq!(
+            local_handoff("app", test_env(&dir)),
+            remote_render("app", &dir.path("work"), test_env(&dir))
+        );
+    }
+
+    #[test]
+    fn local_and_remote_agree_by_path() {
+        let dir = test_dir("path");
+        let local = local_handoff(&dir.path("work/local.toml"), test_env(&dir));
+        assert_eq!(
+            local,
+            remote_render(&dir.path("work/local.toml"), "/nonexistent", test_env(&dir))
+        );
+        assert_eq!(
+            local,
+            remote_render("work/local.toml", &dir.path(""), test_env(&dir))
+        );
+    }
+
+    #[test]
+    fn verify_ignores_step_variables() {
+        let dir = test_dir("verify");
+        dir.write(
+            "edfs/step.toml",
+            "image = \"alpine:3.20\"\nworkdir = \"/work\"\n\n[env]\nRANK = \"${SLURM_PROCID}\"\n",
+        );
+
+        let mut submit = test_env(&dir);
+        submit.insert(String::from("SLURM_PROCID"), String::from("0"));
+        let handoff = edf_render(String::from("step"), submit, None).unwrap();
+
+        let mut task = test_env(&dir);
+        task.insert(String::from("SLURM_PROCID"), String::from("3"));
+        let differences = edf_verify(&handoff, String::from("step"), task.clone(), None);
+        assert!(differences.unwrap().is_empty());
+
+        // Fields that don't depend on the environment are still compared.
+        let mut tampered = handoff.clone();
+        tampered.image = String::from("evil:latest");
+        let differences = edf_verify(&tampered, String::from("step"), task, None);
+        assert_eq!(differences.unwrap(), vec!["/image"]);
+    }
+
+    #[test]
+    fn remote_path_relative_to_submit_cwd() {
+        assert_eq!(edf_remote_path("app", "/home/u"), "app");
+        assert_eq!(
+            edf_remote_path("/etc/edf/app.toml", "/home/u"),
+            "/etc/edf/app.toml"
+        );
+        assert_eq!(
+            edf_remote_path("edf/app.toml", "/home/u"),
+            "/home/u/edf/app.toml"
+        );
+        assert_eq!(edf_remote_path("edf/app.toml", ""), "edf/app.toml");
+    }
+
+    fn mount_defaults_for(dir: &TestDir) -> MountDefaults {
+        MountDefaults {
+            policy: DefaultMounts {
+                home: false,
+                submit_dir: true,
+                scratch_env: String::from("SCRATCH"),
+                slurm: false,
+            },
+            constraints: EdfConstraints::default(),
+            mount_home: None,
+            uid: getuid().as_raw(),
+            gid: getgid().as_raw(),
+            submit_dir: dir.path("work"),
+            scratch: dir.path("scratch"),
+        }
+    }
+
+    #[test]
+    fn default_mounts_added_at_render() {
+        let dir = test_dir("defaults");
+        let defaults = mount_defaults_for(&dir);
+
+        // The scratch directory doesn't exist, only the submit dir is added.
+        let edf = edf_render(String::from("app"), test_env(&dir), Some(&defaults)).unwrap();
+        assert_eq!(edf.mounts.len(), 1);
+
+        dir.mkdir("scratch");
+        let edf = edf_render(String::from("app"), test_env(&dir), Some(&defaults)).unwrap();
+        assert_eq!(edf.mounts.len(), 2);
+
+        // Rendering again over the result doesn't mount twice.
+        let mut again = edf.clone();
+        edf_add_default_mounts(&mut again, &defaults);
+        assert_eq!(
+            serde_json::to_value(&again).unwrap(),
+            serde_json::to_value(&edf).unwrap()
+        );
+    }
+
+    fn edf_from_toml(toml: &str) -> raster::EDF {
+        raster::get_edf_from_string(String::from(toml)).unwrap()
+    }
+
+    #[test]
+    fn sbatch_mounts_spool_dir() {
+        let mut edf = edf_from_toml("image = \"alpine:3.20\"\n");
+        edf_add_spool_mount(&mut edf, "/var/spool/slurmd/job00042/slurm_script").unwrap();
+
+        let mounts = serde_json::to_value(&edf.mounts).unwrap();
+        let mount = &mounts.as_array().unwrap()[0];
+        assert_eq!(edf.mounts.len(), 1);
+        assert_eq!(mount["source"], "/var/spool/slurmd/job00042");
+        assert_eq!(mount["destination"], "/var/spool/slurmd/job00042");
+        assert_eq!(mount["flags"], "bind,ro,nosuid,nodev,private");
+
+        // A script without a parent directory is mounted by itself.
+        let mut edf = edf_from_toml("image = \"alpine:3.20\"\n");
+        edf_add_spool_mount(&mut edf, "/slurm_script").unwrap();
+        let mounts = serde_json::to_value(&edf.mounts).unwrap();
+        assert_eq!(mounts[0]["source"], "/slurm_script");
+    }
+
+    #[test]
+    fn slurm_client_default_on_for_batch_step() {
+        let edf = edf_from_toml("image = \"alpine:3.20\"\n");
+        assert!(slurm_client_enabled(&edf, SLURM_BATCH_SCRIPT));
+        assert!(!slurm_client_enabled(&edf, 0));
+
+        let edf = edf_from_toml(
+            "image = \"alpine:3.20\"\n\n[annotations]\n\"skybox.slurm_client\" = \"false\"\n",
+        );
+        assert!(!slurm_client_enabled(&edf, SLURM_BATCH_SCRIPT));
+
+        let edf = edf_from_toml(
+            "image = \"alpine:3.20\"\n\n[annotations]\n\"skybox.slurm_client\" = \"true\"\n",
+        );
+        assert!(slurm_client_enabled(&edf, 0));
+    }
+
+    #[test]
+    fn search_paths_from_env() {
+        let mut env = HashMap::new();
+        assert!(edf_user_search_paths(&env).is_empty());
+
+        env.insert(String::from("HOME"), String::from("/home/u"));
+        assert_eq!(edf_user_search_paths(&env), vec!["/home/u/.edf"]);
+
+        env.insert(String::from("EDF_PATH"), String::from("/opt/edf"));
+        assert_eq!(edf_user_search_paths(&env), vec!["/opt/edf"]);
+    }
+}
@@ -0,0 +1,224 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use crate::config::CommandVerbosity;
+use crate::report::{command_output_timeout, report_command};
+use crate::{
+    SpankSkyBox, create_folder, plugin_err, plugin_string, skybox_log_error, skybox_log_info,
+};
+
+/// Check that podman, the imagestore and the mount program work on this
+/// node. A failing check leaves a marker with the reason, which makes steps
+/// refuse --edf early, and runs the optional `skybox_node_unhealthy` hook
+/// (e.g. to drain the node).
+pub(crate) fn health_check(ssb: &SpankSkyBox) {
+    let health = &ssb.skybox_config.health;
+    if !health.enabled {
+        return;
+    }
+
+    match health_probe(ssb) {
+        Ok(_) => {
+            if Path::new(&health.marker_path).exists() {
+                skybox_log_info!("node health check passed, clearing {}", health.marker_path);
+                let _ = std::fs::remove_file(&health.marker_path);
+            }
+        }
+        Err(e) => {
+            let reason = e.to_string();
+            skybox_log_error!("node health check failed: {reason}");
+
+            if let Err(e) = health_write_marker(&health.marker_path, &reason) {
+                skybox_log_error!("cannot write {}: {e}", health.marker_path);
+            }
+
+            let t0 = Instant::now();
+            match raster::hook_run(&ssb.config, "skybox_node_unhealthy", vec![&reason]) {
+                Ok(Some(ec)) => report_command(
+                    &ec.command,
+                    &ec.output,
+                    "skybox_node_unhealthy hook",
+                    CommandVerbosity::Error,
+                    t0.elapsed(),
+                ),
+                Ok(None) => (),
+                Err(e) => skybox_log_error!("skybox_node_unhealthy hook failed: {e}"),
+            }
+        }
+    }
+}
+
+/// Refuse containers on a node marked unhealthy.
+pub(crate) fn health_refuse_if_marked(ssb: &SpankSkyBox) -> Result<(), Box<dyn Error>> {
+    let health = &ssb.skybox_config.health;
+    if !health.enabled {
+        return Ok(());
+    }
+
+    let reason = match std::fs::read_to_string(&health.marker_path) {
+        Ok(r) => r,
+        Err(_) => return Ok(()),
+    };
+
+    let host = sysinfo::System::host_name().unwrap_or_default();
+    plugin_err(&format!(
+        "node {host} cannot run containers at the moment: {}",
+        reason.trim()
+    ))
+}
+
+fn health_write_marker(path: &str, reason: &str) -> Result<(), Box<dyn Error>> {
+    if let Some(dir) = Path::new(path).parent() {
+        create_folder(dir.to_string_lossy().to_string(), 0o755)?;
+    }
+    std::fs::write(path, format!("{reason}\n"))?;
+    Ok(())
+}
+
+fn health_probe(ssb: &SpankSkyBox) -> Result<(), Box<dyn Error>> {
+    let health = &ssb.skybox_config.health;
+
+    // Scratch podman storage next to the marker, podman_tmp_path may still
+    // depend on job variables here. One per probe, epilogs of jobs ending
+    // together run at the same time.
+    let base = match Path::new(&health.marker_path).parent() {
+        Some(d) => d.to_path_buf(),
+        None => PathBuf::from("/run/skybox"),
+    };
+    let base = base.join(format!("health-{}", std::process::id()));
+
+    let result = health_probe_in(ssb, &base);
+
+    if let Err(e) = std::fs::remove_dir_all(&base) {
+        skybox_log_error!("cannot remove health check storage {}: {e}", base.display());
+    }
+    result
+}
+
+fn health_probe_in(ssb: &SpankSkyBox, base: &Path) -> Result<(), Box<dyn Error>> {
+    let config = &ssb.config;
+    let health = &ssb.skybox_config.health;
+
+    let _ = std::fs::remove_dir_all(base);
+    let graphroot = base.join("graphroot");
+    let runroot = base.join("runroot");
+    let home = base.join("home");
+    let xdg_config = base.join("config");
+    for dir in [base, &graphroot, &runroot, &home, &xdg_config] {
+        create_folder(dir.to_string_lossy().to_string(), 0o700)?;
+    }
+
+    // Runs as root, with a home and config dirs of its own: only the site
+    // configuration of podman applies, never the one of a user.
+    let probe = HealthPodman {
+        config,
+        graphroot: graphroot.to_string_lossy().to_string(),
+        runroot: runroot.to_string_lossy().to_string(),
+        env: vec![
+            (String::from("HOME"), home.to_string_lossy().to_string()),
+            (
+                String::from("XDG_CONFIG_HOME"),
+                xdg_config.to_string_lossy().to_string(),
+            ),
+            (
+                String::from("XDG_RUNTIME_DIR"),
+                runroot.to_string_lossy().to_string(),
+            ),
+        ],
+        // One budget for the whole check, slurmd start and the epilog wait on it.
+        deadline: Instant::now() + Duration::from_millis(health.timeout_ms),
+    };
+
+    probe.run("info", &["info"])?;
+
+    // Listing the images opens the imagestore.
+    let images = probe.run("images", &["images", "--quiet", "--no-trunc"])?;
+    let image = match images.lines().map(str::trim).find(|l| !l.is_empty()) {
+        Some(i) => String::from(i),
+        None => {
+            skybox_log_info!(
+                "imagestore {} is empty, not checking the mount program",
+                config.parallax_imagestore
+            );
+            return Ok(());
+        }
+    };
+
+    // Mounting an image of the imagestore goes through the mount program.
+    probe.run("image mount", &["image", "mount", &image])?;
+    probe.run("image unmount", &["image", "unmount", &image])?;
+    Ok(())
+}
+
+// Podman with the storage, module and mount program of the job containers.
+struct HealthPodman<'a> {
+    config: &'a raster::Config,
+    graphroot: String,
+    runroot: String,
+    env: Vec<(String, String)>,
+    deadline: Instant,
+}
+
+impl HealthPodman<'_> {
+    // Run a podman subcommand and return its stdout, failing on a non zero exit.
+    fn run(&self, what: &str, args: &[&str]) -> Result<String, Box<dyn Error>> {
+        let config = self.config;
+        let podman_path = &config.podman_path;
+
+        let mut command = Command::new(podman_path);
+        command
+            .arg("--root")
+            .arg(&self.graphroot)
+            .arg("--runroot")
+            .arg(&self.runroot)
+            .arg("--storage-opt")
+            .arg(format!(
+                "additionalimagestore={}",
+                config.parallax_imagestore
+            ))
+            .arg("--storage-opt")
+            .arg(format!("mount_program={}", config.parallax_mount_program));
+        if !config.podman_module.is_empty() {
+            command.arg("--module").arg(&config.podman_module);
+        }
+        command
+            .args(args)
+            .env("PARALLAX_MP_UID", config.parallax_mp_uid.to_string())
+            .env("PARALLAX_MP_GID", config.parallax_mp_gid.to_string())
+            .env(
+                "PARALLAX_MP_SQUASHFUSE_CMD",
+                &config.parallax_mp_squashfuse_path,
+            )
+            .env("PARALLAX_MP_LOGFILE", &config.parallax_mp_logfile)
+            .envs(self.env.iter().cloned());
+
+        let t0 = Instant::now();
+        let timeout = self.deadline.saturating_duration_since(t0);
+        let output = match command_output_timeout(&mut command, timeout) {
+            Ok(o) => o,
+            Err(e) => {
+                let msg = format!("podman {what}: {e}");
+                return Err(plugin_string(&msg).into());
+            }
+        };
+        report_command(
+            format!("{:?}", command),
+            &output,
+            &format!("health podman {what}"),
+            CommandVerbosity::Debug,
+            t0.elapsed(),
+        );
+
+        if !output.status.success() {
+            let reason = String::from_utf8_lossy(&output.stderr);
+            let msg = match reason.lines().last() {
+                Some(l) => format!("podman {what} exited with {}: {l}", output.status),
+                None => format!("podman {what} exited with {}", output.status),
+            };
+            return Err(plugin_string(&msg).into());
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
@@ -0,0 +1,136 @@
+use nix::unistd::Pid;
+use serde::Serialize;
+use std::error::Error;
+use std::time::Instant;
+
+use crate::config::{CommandVerbosity, FailPolicy};
+use crate::report::report_command;
+use crate::{SpankSkyBox, skybox_log_debug, skybox_log_error, skybox_log_info};
+
+/// Points of the step lifecycle where site hooks run. The hook is looked up
+/// by raster as "skybox_<point>" and gets a JSON payload as only argument.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum HookPoint {
+    PrePull,
+    PostMigrate,
+    PreStart,
+    PostStart,
+    PostJoin,
+    PreStop,
+    PostCleanup,
+}
+
+impl HookPoint {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            HookPoint::PrePull => "pre_pull",
+            HookPoint::PostMigrate => "post_migrate",
+            HookPoint::PreStart => "pre_start",
+            HookPoint::PostStart => "post_start",
+            HookPoint::PostJoin => "post_join",
+            HookPoint::PreStop => "pre_stop",
+            HookPoint::PostCleanup => "post_cleanup",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct HookPayload<'a> {
+    hook: &'a str,
+    jobid: Option<u32>,
+    stepid: Option<u32>,
+    nodeid: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    local_task_id: Option<u32>,
+    global_task_id: Option<u32>,
+    image: Option<&'a str>,
+    container_name: Option<&'a str>,
+    container_pid: Option<i32>,
+    podman_tmp_path: Option<&'a str>,
+}
+
+fn hook_payload(ssb: &SpankSkyBox, point: HookPoint, pid: Option<Pid>) -> HookPayload<'_> {
+    let job = ssb.job.as_ref();
+    let run = ssb.run.as_ref();
+    // Task ids are u32::MAX outside of the task callbacks.
+    let task_id = |id: u32| if id == u32::MAX { None } else { Some(id) };
+
+    HookPayload {
+        hook: point.name(),
+        jobid: job.map(|j| j.jobid),
+        stepid: job.map(|j| j.stepid),
+        nodeid: job.map(|j| j.nodeid),
+        uid: job.map(|j| j.uid),
+        gid: job.map(|j| j.gid),
+        local_task_id: job.and_then(|j| task_id(j.local_task_id)),
+        global_task_id: job.and_then(|j| task_id(j.global_task_id)),
+        image: ssb.edf.as_ref().map(|e| e.image.as_str()),
+        container_name: run.map(|r| r.name.as_str()),
+        container_pid: pid.map(Pid::as_raw),
+        podman_tmp_path: run.map(|r| r.podman_tmp_path.as_str()),
+    }
+}
+
+fn hook_exec(
+    ssb: &SpankSkyBox,
+    point: HookPoint,
+    pid: Option<Pid>,
+) -> Result<(), Box<dyn Error>> {
+    let hook = format!("skybox_{}", point.name());
+    let payload = serde_json::to_string(&hook_payload(ssb, point, pid))?;
+
+    let t0 = Instant::now();
+    let ec = match raster::hook_run(&ssb.config, &hook, vec![&payload])? {
+        Some(ec) => ec,
+        None => return Ok(()),
+    };
+
+    let prefix = format!("{hook} hook");
+    report_command(
+        &ec.command,
+        &ec.output,
+        &prefix,
+        CommandVerbosity::User,
+        t0.elapsed(),
+    );
+
+    match ec.output.status.code() {
+        Some(0) => Ok(()),
+        Some(rc) => Err(format!("{prefix} exited with {rc}").into()),
+        None => Err(format!("{prefix} exited by signal").into()),
+    }
+}
+
+/// Run the site hook of a lifecycle point, if any, and apply its failure policy.
+pub(crate) fn hook_point_run(
+    ssb: &SpankSkyBox,
+    point: HookPoint,
+    pid: Option<Pid>,
+) -> Result<(), Box<dyn Error>> {
+    let e = match hook_exec(ssb, point, pid) {
+        Ok(_) => return Ok(()),
+        Err(e) => e,
+    };
+
+    let hooks = &ssb.skybox_config.hooks;
+    let policy = *hooks
+        .policies
+        .get(point.name())
+        .unwrap_or(&hooks.fail_policy);
+
+    match policy {
+        FailPolicy::Fail => {
+            skybox_log_error!("{} hook failed: {e}", point.name());
+            Err(e)
+        }
+        FailPolicy::Warn => {
+            skybox_log_info!("warning: {} hook failed: {e}", point.name());
+            Ok(())
+        }
+        FailPolicy::Ignore => {
+            skybox_log_debug!("{} hook failed: {e}", point.name());
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use serde::Serialize;
+use std::error::Error;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use slurm_spank::SpankHandle;
+
+use crate::config::{CommandVerbosity, JobInfoConfig, JobInfoSource};
+use crate::report::{command_output_timeout, report_command};
+use crate::{SpankSkyBox, plugin_string, spank_getenv};
+
+/// Partition, QOS and account of the job on a compute node.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub(crate) struct JobInfo {
+    pub(crate) partition: String,
+    pub(crate) qos: String,
+    pub(crate) account: String,
+}
+
+/// Job values of the step, read once and then cached. Asking slurmctld
+/// costs an RPC per step and node, the default reads the variables Slurm sets
+/// in the step environment.
+pub(crate) fn job_info(
+    ssb: &mut SpankSkyBox,
+    spank: &mut SpankHandle,
+) -> Result<JobInfo, Box<dyn Error>> {
+    if let Some(info) = &ssb.job_info {
+        return Ok(info.clone());
+    }
+
+    let config = &ssb.skybox_config.job_info;
+    let info = match config.source {
+        JobInfoSource::Env => job_info_env(spank)?,
+        JobInfoSource::Slurmctld => job_info_load(config, spank.job_id()?)?,
+    };
+    ssb.job_info = Some(info.clone());
+    Ok(info)
+}
+
+// Every step gets SLURM_JOB_PARTITION, QOS and account only with accounting.
+fn job_info_env(spank: &mut SpankHandle) -> Result<JobInfo, Box<dyn Error>> {
+    let info = JobInfo {
+        partition: spank_getenv(spank, "SLURM_JOB_PARTITION"),
+        qos: spank_getenv(spank, "SLURM_JOB_QOS"),
+        account: spank_getenv(spank, "SLURM_JOB_ACCOUNT"),
+    };
+
+    if info.partition.is_empty() {
+        return Err(plugin_string("SLURM_JOB_PARTITION is not set in the step environment").into());
+    }
+
+    Ok(info)
+}
+
+fn job_info_load(config: &JobInfoConfig, jobid: u32) -> Result<JobInfo, Box<dyn Error>> {
+    let mut command = Command::new(&config.scontrol);
+    command
+        .arg("--json")
+        .arg("show")
+        .arg("job")
+        .arg(jobid.to_string());
+
+    let t0 = Instant::now();
+    let timeout = Duration::from_millis(config.timeout_ms);
+    let output = command_output_timeout(&mut command, timeout)?;
+    report_command(
+        format!("{:?}", command),
+        &output,
+        "scontrol show job",
+        CommandVerbosity::Debug,
+        t0.elapsed(),
+    );
+
+    if !output.status.success() {
+        let msg = format!("cannot get job {jobid} from slurmctld: {}", output.status);
+        return Err(plugin_string(&msg).into());
+    }
+
+    job_info_parse(&String::from_utf8_lossy(&output.stdout), jobid)
+}
+
+fn job_info_parse(json: &str, jobid: u32) -> Result<JobInfo, Box<dyn Error>> {
+    let value: serde_json::Value = serde_json::from_str(json)?;
+
+    let job = value.get("jobs").and_then(|j| j.as_array()).and_then(|j| {
+        j.iter()
+            .find(|j| j.get("job_id").and_then(|i| i.as_u64()) == Some(jobid.into()))
+    });
+
+    let job = match job {
+        Some(j) => j,
+        None => {
+            let msg = format!("slurmctld returned no record for job {jobid}");
+            return Err(plugin_string(&msg).into());
+        }
+    };
+
+    let field = |name: &str| {
+        job.get(name)
+            .and_then(|v| v.as_str())
+            .map(String::from)
+            .unwrap_or_default()
+    };
+
+    Ok(JobInfo {
+        partition: field("partition"),
+        qos: field("qos"),
+        account: field("account"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_job() {
+        let json = r#"{"jobs": [{"job_id": 42, "name": "x Partition=normal",
+            "partition": "debug", "qos": "normal", "account": "proj1"}]}"#;
+        let info = job_info_parse(json, 42).unwrap();
+        assert_eq!(
+            info,
+            JobInfo {
+                partition: String::from("debug"),
+                qos: String::from("normal"),
+                account: String::from("proj1"),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_other_job() {
+        let json = r#"{"jobs": [{"job_id": 7, "partition": "debug"}]}"#;
+        assert!(job_info_parse(json, 42).is_err());
+        assert!(job_info_parse(r#"{"jobs": []}"#, 42).is_err());
+        assert!(job_info_parse("not json", 42).is_err());
+    }
+}
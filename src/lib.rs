@@ -1,39 +1,61 @@
 use nix::libc::{gid_t, uid_t};
 use serde::Serialize;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
 use std::fs::Permissions;
 use std::os::unix::fs::{PermissionsExt, chown};
 //use std::os::raw::c_int;
 use std::path::Path;
 //use std::sync::{Arc, Mutex};
-use nix::unistd::{getegid, geteuid};
+use nix::unistd::{Pid, getegid, geteuid};
+use sysinfo::System;
 
 use slurm_spank::Context;
 use slurm_spank::{Plugin, SLURM_VERSION_NUMBER, SPANK_PLUGIN, SpankHandle};
 
 //use raster::mount::SarusMounts;
 use crate::args::SkyBoxArgs;
-//use crate::config::SkyBoxConfig;
+use crate::config::SkyBoxConfig;
+use crate::jobinfo::JobInfo;
+use crate::logging::{log_set_job, log_set_task};
+use crate::perf::Perf;
+use crate::plugstack::PlugstackArgs;
 use crate::podman::podman_get_pid_from_file;
+use crate::tracking::unix_time_now;
 //use crate::environment::SkyBoxEDF;
 use raster::{Config, EDF};
 
+pub mod admission;
 pub mod alloc;
 pub mod args;
 pub mod config;
+pub mod constraints;
 pub mod container;
 pub mod dispatch;
 pub mod edf;
+pub mod health;
+pub mod hooks;
+pub mod jobinfo;
+pub mod logging;
+pub mod perf;
+pub mod plugstack;
 pub mod podman;
+pub mod redact;
+pub mod report;
 pub mod slurmd;
 pub mod slurmstepd;
 pub mod srun;
 pub mod sync;
 pub mod tracking;
+pub mod validate;
 
 pub(crate) const SLURM_BATCH_SCRIPT: u32 = 0xfffffffb;
 pub(crate) const VERSION: &str = env!("CARGO_PKG_VERSION");
+// Exported to container tasks, used to detect skybox steps launched from inside a container.
+pub(crate) const SKYBOX_CONTAINER_ENV: &str = "SKYBOX_CONTAINER";
+pub(crate) const SKYBOX_EDF_ENV: &str = "SKYBOX_EDF";
+// Default EDF picked at submission, used by the nodes when there is no --edf.
+pub(crate) const SKYBOX_EDF_DEFAULT_ENV: &str = "SKYBOX_EDF_DEFAULT";
 
 SPANK_PLUGIN!(b"skybox", SLURM_VERSION_NUMBER, SpankSkyBox);
 
@@ -58,9 +80,14 @@ struct SpankSkyBox {
     */
     args: SkyBoxArgs,
     config: Config,
+    skybox_config: SkyBoxConfig,
     edf: Option<EDF>,
     job: Option<Job>,
     run: Option<Run>,
+    perf: Perf,
+    config_sources: BTreeMap<String, String>,
+    plugstack: PlugstackArgs,
+    job_info: Option<JobInfo>,
 }
 
 #[derive(Clone, Serialize, Default)]
@@ -77,48 +104,55 @@ struct Job {
     cwd: String,
     euid: uid_t,
     egid: gid_t,
+    start_time: u64,
+    exit_code: Option<i32>,
 }
 
 #[derive(Clone, Serialize, Default)]
 struct Run {
     name: String,
-    pid: usize,
+    #[serde(serialize_with = "serialize_pid")]
+    pid: Option<Pid>,
     podman_tmp_path: String,
     syncfile_path: String,
 }
 
+fn serialize_pid<S: serde::Serializer>(pid: &Option<Pid>, s: S) -> Result<S::Ok, S::Error> {
+    pid.map(Pid::as_raw).serialize(s)
+}
+
 #[macro_export]
 macro_rules! skybox_log_debug {
     ($($arg:tt)*) => ({
-        slurm_spank::spank_log(slurm_spank::LogLevel::Debug, &format!("[{}] {}", $crate::get_plugin_name(), &format!($($arg)*)));
+        $crate::logging::log_message(slurm_spank::LogLevel::Debug, "debug", &format!($($arg)*));
     })
 }
 
 #[macro_export]
 macro_rules! skybox_log_error {
     ($($arg:tt)*) => ({
-        slurm_spank::spank_log(slurm_spank::LogLevel::Error, &format!("[{}] {}", $crate::get_plugin_name(), &format!($($arg)*)));
+        $crate::logging::log_message(slurm_spank::LogLevel::Error, "error", &format!($($arg)*));
     })
 }
 
 #[macro_export]
 macro_rules! skybox_log_info {
     ($($arg:tt)*) => ({
-        slurm_spank::spank_log(slurm_spank::LogLevel::Info, &format!("[{}] {}", $crate::get_plugin_name(), &format!($($arg)*)));
+        $crate::logging::log_message(slurm_spank::LogLevel::Info, "info", &format!($($arg)*));
     })
 }
 
 #[macro_export]
 macro_rules! skybox_log_user {
     ($($arg:tt)*) => ({
-        slurm_spank::slurm_spank_log(&format!("[{}] {}", $crate::get_plugin_name(), &format!($($arg)*)));
+        $crate::logging::log_user(&format!($($arg)*));
     })
 }
 
 #[macro_export]
 macro_rules! skybox_log_verbose {
     ($($arg:tt)*) => ({
-        slurm_spank::spank_log(slurm_spank::LogLevel::Verbose, &format!("[{}] {}", $crate::get_plugin_name(), &format!($($arg)*)));
+        $crate::logging::log_message(slurm_spank::LogLevel::Verbose, "verbose", &format!($($arg)*));
     })
 }
 
@@ -183,10 +217,13 @@ pub(crate) fn job_get_info(
         cwd: cwd,
         euid: geteuid().as_raw(),
         egid: getegid().as_raw(),
+        start_time: unix_time_now(),
+        exit_code: None,
     });
 
     // grab euid to use by squashfuse downstream to mount program
     if let Some(job) = ssb.job.as_ref() {
+        log_set_job(job.jobid, job.stepid, System::host_name());
         skybox_log_debug!(
             "job uid/gid={}/{} euid/egid={}/{}",
             job.uid,
@@ -211,6 +248,7 @@ pub(crate) fn task_set_info(
         Some(j) => {
             j.local_task_id = local_task_id;
             j.global_task_id = global_task_id;
+            log_set_task(local_task_id, global_task_id);
         }
         None => {
             return plugin_err("couldn't find job structure");
@@ -235,10 +273,7 @@ pub(crate) fn run_set_info(
     let podman_tmp_path = format!("{}/{}", config.podman_tmp_path, name);
     let syncfile_path = format!("{}/.{}_import.done", config.parallax_imagestore, name);
 
-    let pid = match podman_get_pid_from_file(ssb) {
-        Ok(s) => s,
-        Err(_) => usize::MAX,
-    };
+    let pid = podman_get_pid_from_file(ssb).ok();
 
     ssb.run = Some(Run {
         name: name,
@@ -344,27 +379,42 @@ pub(crate) fn get_job_env(spank: &mut SpankHandle) -> HashMap<String, String> {
 
     let jobenv = match spank.job_env() {
         Ok(j) => j,
-        Err(_) => {
+        Err(e) => {
+            skybox_log_debug!("couldn't read job environment: {e}");
             return user_env;
         }
     };
 
     for e in jobenv.iter() {
-        let mut split = e.split("=");
-
-        let size = split.clone().count();
-        if size < 2 || size > 3 {
-            continue;
+        match parse_env_entry(e.as_bytes()) {
+            Some((k, v)) => {
+                user_env.insert(k, v);
+            }
+            None => {
+                skybox_log_debug!("skipping malformed job env entry \"{e}\"");
+            }
         }
-
-        let k = split.next().unwrap();
-        let v = split.next().unwrap();
-        user_env.insert(String::from(k), String::from(v));
     }
 
     user_env
 }
 
+// Split a "KEY=VALUE" environment entry on the first '='.
+// Values may contain '=' and non UTF-8 bytes, which are decoded lossily.
+pub(crate) fn parse_env_entry(entry: &[u8]) -> Option<(String, String)> {
+    let pos = entry.iter().position(|b| *b == b'=')?;
+    let (key, value) = (&entry[..pos], &entry[pos + 1..]);
+
+    if key.is_empty() {
+        return None;
+    }
+
+    Some((
+        String::from_utf8_lossy(key).into_owned(),
+        String::from_utf8_lossy(value).into_owned(),
+    ))
+}
+
 pub(crate) fn remote_unset_env_vars(
     ssb: &mut SpankSkyBox,
     spank: &mut SpankHandle,
@@ -430,3 +480,73 @@ pub(crate) fn skybox_log_context(
 
     Ok(())
 }
+
+/// Scratch directory of a test, removed when dropped. Names must be unique
+/// within the test binary.
+#[cfg(test)]
+pub(crate) struct TestDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    pub(crate) fn new(name: &str) -> TestDir {
+        let dir = std::env::temp_dir().join(format!("skybox-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        TestDir(dir)
+    }
+
+    pub(crate) fn path(&self, rel: &str) -> String {
+        self.0.join(rel).to_string_lossy().to_string()
+    }
+
+    pub(crate) fn mkdir(&self, rel: &str) {
+        std::fs::create_dir_all(self.0.join(rel)).unwrap();
+    }
+
+    pub(crate) fn write(&self, rel: &str, content: &str) {
+        std::fs::write(self.0.join(rel), content).unwrap();
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_env_entry;
+
+    fn entry(key: &str, value: &str) -> Option<(String, String)> {
+        Some((String::from(key), String::from(value)))
+    }
+
+    #[test]
+    fn env_entry_empty_value() {
+        assert_eq!(parse_env_entry(b"FOO="), entry("FOO", ""));
+    }
+
+    #[test]
+    fn env_entry_missing_equal() {
+        assert_eq!(parse_env_entry(b"FOO"), None);
+        assert_eq!(parse_env_entry(b""), None);
+    }
+
+    #[test]
+    fn env_entry_value_with_equal() {
+        assert_eq!(parse_env_entry(b"OPTS=a=1,b=2"), entry("OPTS", "a=1,b=2"));
+        assert_eq!(parse_env_entry(b"X=="), entry("X", "="));
+    }
+
+    #[test]
+    fn env_entry_empty_key() {
+        assert_eq!(parse_env_entry(b"=value"), None);
+    }
+
+    #[test]
+    fn env_entry_non_utf8() {
+        assert_eq!(parse_env_entry(b"K=a\xffb"), entry("K", "a\u{fffd}b"));
+    }
+}
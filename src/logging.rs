@@ -0,0 +1,245 @@
+use serde::Serialize;
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use nix::unistd::{Gid, Uid, setfsgid, setfsuid};
+use slurm_spank::{LogLevel, SpankHandle};
+
+use crate::edf::edf_annotation_enabled;
+use crate::tracking::unix_time_now;
+use crate::{SpankSkyBox, get_plugin_name, skybox_log_user, spank_getenv};
+
+/// Fields added to every log line in structured mode. Updated as the plugin
+/// goes through the spank callbacks.
+#[derive(Serialize)]
+struct LogContext {
+    #[serde(skip)]
+    structured: bool,
+    #[serde(skip)]
+    phase_start: Option<Instant>,
+    jobid: Option<u32>,
+    stepid: Option<u32>,
+    node: Option<String>,
+    local_task_id: Option<u32>,
+    global_task_id: Option<u32>,
+    phase: Option<&'static str>,
+}
+
+static LOG_CONTEXT: Mutex<LogContext> = Mutex::new(LogContext {
+    structured: false,
+    phase_start: None,
+    jobid: None,
+    stepid: None,
+    node: None,
+    local_task_id: None,
+    global_task_id: None,
+    phase: None,
+});
+
+// Per step debug log, every skybox message is mirrored there when open.
+static DEBUG_FILE: Mutex<Option<File>> = Mutex::new(None);
+
+/// Most verbose skybox messages passed to Slurm, set by `log_level=` in
+/// plugstack.conf. The debug log always gets everything.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum LogThreshold {
+    Error,
+    Info,
+    Verbose,
+    Debug,
+}
+
+impl LogThreshold {
+    pub(crate) fn from_name(name: &str) -> Option<LogThreshold> {
+        match name {
+            "error" => Some(LogThreshold::Error),
+            "info" => Some(LogThreshold::Info),
+            "verbose" => Some(LogThreshold::Verbose),
+            "debug" => Some(LogThreshold::Debug),
+            _ => None,
+        }
+    }
+}
+
+static LOG_THRESHOLD: Mutex<LogThreshold> = Mutex::new(LogThreshold::Debug);
+
+#[derive(Serialize)]
+struct LogLine<'a> {
+    plugin: String,
+    level: &'a str,
+    msg: &'a str,
+    elapsed_ms: Option<u128>,
+    #[serde(flatten)]
+    context: &'a LogContext,
+}
+
+/// Format a log message, plain "[skybox] msg" unless structured mode is on.
+pub fn log_format(level: &str, msg: &str) -> String {
+    let plain = format!("[{}] {}", get_plugin_name(), msg);
+
+    let ctx = match LOG_CONTEXT.lock() {
+        Ok(c) => c,
+        Err(_) => return plain,
+    };
+
+    if !ctx.structured {
+        return plain;
+    }
+
+    let line = LogLine {
+        plugin: get_plugin_name(),
+        level,
+        msg,
+        elapsed_ms: ctx.phase_start.map(|t| t.elapsed().as_millis()),
+        context: &ctx,
+    };
+
+    match serde_json::to_string(&line) {
+        Ok(json) => format!("[{}] {}", get_plugin_name(), json),
+        Err(_) => plain,
+    }
+}
+
+/// Log a message through spank and mirror it to the debug file.
+pub fn log_message(level: LogLevel, name: &str, msg: &str) {
+    let line = log_format(name, msg);
+    log_mirror(name, &line);
+
+    let threshold = match LOG_THRESHOLD.lock() {
+        Ok(t) => *t,
+        Err(_) => LogThreshold::Debug,
+    };
+    match LogThreshold::from_name(name) {
+        Some(l) if l > threshold => (),
+        _ => slurm_spank::spank_log(level, &line),
+    }
+}
+
+/// Log a message for the user, never structured.
+pub fn log_user(msg: &str) {
+    let line = format!("[{}] {}", get_plugin_name(), msg);
+    log_mirror("user", &line);
+    slurm_spank::slurm_spank_log(&line);
+}
+
+fn log_mirror(name: &str, line: &str) {
+    if let Ok(mut f) = DEBUG_FILE.lock()
+        && let Some(file) = f.as_mut()
+    {
+        let _ = writeln!(file, "{} {:<7} {}", unix_time_now(), name, line);
+    }
+}
+
+pub(crate) fn log_open_debug_file(path: &str) -> Result<(), Box<dyn Error>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(path)?;
+
+    if let Ok(mut f) = DEBUG_FILE.lock() {
+        *f = Some(file);
+    }
+    Ok(())
+}
+
+pub(crate) fn log_close_debug_file() {
+    if let Ok(mut f) = DEBUG_FILE.lock() {
+        *f = None;
+    }
+}
+
+fn log_context_update<F: FnOnce(&mut LogContext)>(f: F) {
+    if let Ok(mut ctx) = LOG_CONTEXT.lock() {
+        f(&mut ctx);
+    }
+}
+
+pub(crate) fn log_set_threshold(threshold: LogThreshold) {
+    if let Ok(mut t) = LOG_THRESHOLD.lock() {
+        *t = threshold;
+    }
+}
+
+pub(crate) fn log_set_structured(structured: bool) {
+    log_context_update(|ctx| ctx.structured = structured);
+}
+
+/// Called on entry of every spank callback.
+pub(crate) fn log_set_phase(phase: &'static str) {
+    log_context_update(|ctx| {
+        ctx.phase = Some(phase);
+        ctx.phase_start = Some(Instant::now());
+    });
+}
+
+pub(crate) fn log_set_job(jobid: u32, stepid: u32, node: Option<String>) {
+    log_context_update(|ctx| {
+        ctx.jobid = Some(jobid);
+        ctx.stepid = Some(stepid);
+        ctx.node = node;
+    });
+}
+
+pub(crate) fn log_set_task(local_task_id: u32, global_task_id: u32) {
+    log_context_update(|ctx| {
+        ctx.local_task_id = Some(local_task_id);
+        ctx.global_task_id = Some(global_task_id);
+    });
+}
+
+/// Open the per step debug log when asked by --edf-debug or the EDF, as the
+/// job user so the file is theirs. Parallax mount program logs go next to it.
+pub(crate) fn debug_log_setup(
+    ssb: &mut SpankSkyBox,
+    spank: &mut SpankHandle,
+) -> Result<(), Box<dyn Error>> {
+    let edf = match &ssb.edf {
+        Some(e) => e,
+        None => return Ok(()),
+    };
+
+    if !ssb.args.debug && !edf_annotation_enabled(edf, "skybox.debug", false) {
+        return Ok(());
+    }
+
+    let (job, run) = match (&ssb.job, &ssb.run) {
+        (Some(j), Some(r)) => (j, r),
+        _ => return Ok(()),
+    };
+
+    let mut dir = ssb.skybox_config.debug_log_dir.clone();
+    if dir.is_empty() {
+        dir = spank_getenv(spank, "SLURM_SUBMIT_DIR");
+    }
+    if dir.is_empty() {
+        dir = job.cwd.clone();
+    }
+
+    let path = format!("{}/{}.node{}.log", dir, run.name, job.nodeid);
+
+    let old_uid = setfsuid(Uid::from(job.uid));
+    let old_gid = setfsgid(Gid::from(job.gid));
+    let result = log_open_debug_file(&path);
+    let _ = setfsgid(old_gid);
+    let _ = setfsuid(old_uid);
+
+    match result {
+        Ok(_) => {
+            if job.nodeid == 0 {
+                skybox_log_user!("writing debug log to {path}");
+            }
+            ssb.config.parallax_mp_logfile = format!("{path}.parallax_mp");
+        }
+        Err(e) => {
+            skybox_log_user!("WARNING: cannot write debug log {path}: {e}");
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,163 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::time::Duration;
+
+use crate::{SpankSkyBox, create_folder, skybox_log_debug};
+
+/// Phase timings of a step on this node, in milliseconds.
+#[derive(Clone, Serialize, Default)]
+pub(crate) struct Perf {
+    pub(crate) phases: BTreeMap<String, u64>,
+    pub(crate) image_cache: Option<String>,
+}
+
+/// Record a phase measured in the slurmstepd process.
+pub(crate) fn perf_add(ssb: &mut SpankSkyBox, phase: &str, elapsed: Duration) {
+    let ms = elapsed.as_millis() as u64;
+    let entry = ssb.perf.phases.entry(String::from(phase)).or_insert(0);
+    if ms > *entry {
+        *entry = ms;
+    }
+}
+
+// Tasks run in forked processes, they hand their timings over to slurmstepd
+// through files in the node local podman_tmp_path.
+fn perf_dir(ssb: &SpankSkyBox) -> Option<String> {
+    ssb.run
+        .as_ref()
+        .map(|r| format!("{}/perf", r.podman_tmp_path))
+}
+
+fn perf_write(ssb: &SpankSkyBox, name: &str, value: &str) -> Result<(), Box<dyn Error>> {
+    let dir = match perf_dir(ssb) {
+        Some(d) => d,
+        None => return Ok(()),
+    };
+    create_folder(dir.clone(), 0o700)?;
+
+    let mut file = File::create(format!("{dir}/{name}"))?;
+    write!(file, "{value}")?;
+    Ok(())
+}
+
+/// Record a phase measured in a task process.
+pub(crate) fn perf_share(ssb: &SpankSkyBox, phase: &str, elapsed: Duration) {
+    let task = match &ssb.job {
+        Some(j) => j.global_task_id,
+        None => u32::MAX,
+    };
+    let name = format!("{phase}.{task}");
+
+    if let Err(e) = perf_write(ssb, &name, &elapsed.as_millis().to_string()) {
+        skybox_log_debug!("cannot store {phase} timing: {e}");
+    }
+}
+
+pub(crate) fn perf_share_image_cache(ssb: &SpankSkyBox, hit: bool) {
+    let value = if hit { "hit" } else { "miss" };
+
+    if let Err(e) = perf_write(ssb, "image_cache", value) {
+        skybox_log_debug!("cannot store image cache result: {e}");
+    }
+}
+
+// Phases measured in task processes, see perf_share.
+const PERF_SHARED_PHASES: [&str; 3] = ["join", "podman_run", "pull"];
+
+// One entry per phase and task, stop reading a directory flooded by the user.
+const PERF_MAX_ENTRIES: usize = 65536;
+
+// The perf directory belongs to the job user and is read by root: don't follow
+// symlinks, don't block on a FIFO and read no more than a value needs.
+fn perf_read(dir: &File, name: &str) -> Option<String> {
+    let path = format!("/proc/self/fd/{}/{name}", dir.as_raw_fd());
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+        .open(path)
+        .ok()?;
+
+    if !file.metadata().ok()?.is_file() {
+        return None;
+    }
+
+    let mut value = String::new();
+    file.take(32).read_to_string(&mut value).ok()?;
+    Some(String::from(value.trim()))
+}
+
+/// Merge the task timings into the slurmstepd ones, keeping the slowest task.
+pub(crate) fn perf_collect(ssb: &mut SpankSkyBox) {
+    let dir = match perf_dir(ssb) {
+        Some(d) => d,
+        None => return,
+    };
+
+    let dir = match OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECTORY | libc::O_NOFOLLOW)
+        .open(&dir)
+    {
+        Ok(d) => d,
+        Err(_) => return,
+    };
+
+    let entries = match std::fs::read_dir(format!("/proc/self/fd/{}", dir.as_raw_fd())) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+
+    for entry in entries.flatten().take(PERF_MAX_ENTRIES) {
+        let name = entry.file_name().to_string_lossy().to_string();
+
+        if name == "image_cache" {
+            match perf_read(&dir, &name).as_deref() {
+                Some(v @ ("hit" | "miss")) => ssb.perf.image_cache = Some(String::from(v)),
+                _ => skybox_log_debug!("ignoring invalid perf entry {name}"),
+            }
+            continue;
+        }
+
+        let phase = match name.rsplit_once('.') {
+            Some((p, task)) if task.parse::<u32>().is_ok() => p,
+            _ => continue,
+        };
+        if !PERF_SHARED_PHASES.contains(&phase) {
+            skybox_log_debug!("ignoring unknown perf entry {name}");
+            continue;
+        }
+
+        match perf_read(&dir, &name).and_then(|v| v.parse::<u64>().ok()) {
+            Some(ms) => perf_add(ssb, phase, Duration::from_millis(ms)),
+            None => skybox_log_debug!("ignoring invalid perf entry {name}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestDir;
+
+    #[test]
+    fn perf_read_untrusted() {
+        let tmp = TestDir::new("perf-read");
+        tmp.write("join.0", "42\n");
+        tmp.write("big", &"7".repeat(1 << 20));
+        std::os::unix::fs::symlink("/etc/hostname", tmp.path("pull.0")).unwrap();
+        let fifo = tmp.path("podman_run.0");
+        nix::unistd::mkfifo(fifo.as_str(), nix::sys::stat::Mode::S_IRWXU).unwrap();
+
+        let dir = File::open(tmp.path("")).unwrap();
+        assert_eq!(perf_read(&dir, "join.0").as_deref(), Some("42"));
+        assert_eq!(perf_read(&dir, "big").map(|v| v.len()), Some(32));
+        assert_eq!(perf_read(&dir, "pull.0"), None);
+        assert_eq!(perf_read(&dir, "podman_run.0"), None);
+        assert_eq!(perf_read(&dir, "missing"), None);
+    }
+}
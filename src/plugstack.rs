@@ -0,0 +1,343 @@
+use serde::Serialize;
+use std::error::Error;
+use std::path::PathBuf;
+
+use raster::Config;
+use slurm_spank::SpankHandle;
+
+use crate::logging::LogThreshold;
+
+/// Options of the skybox line in plugstack.conf, e.g.
+/// `required skybox.so config_path=/etc/sarus/config.toml log_level=info`.
+///
+/// Keys of the main configuration can be set directly (`podman_module=x`),
+/// keys of the `[skybox]` table with a `skybox.` prefix
+/// (`skybox.tracking.fail_policy=fail`). They win over every config file.
+#[derive(Clone, Debug, Default, Serialize)]
+pub(crate) struct PlugstackArgs {
+    pub(crate) config_path: Option<PathBuf>,
+    pub(crate) enabled: Option<bool>,
+    pub(crate) default_edf: Option<String>,
+    pub(crate) log_level: Option<LogThreshold>,
+    pub(crate) required_partitions: Vec<String>,
+    pub(crate) config: toml::Table,
+    pub(crate) skybox: Vec<(String, toml::Value)>,
+    pub(crate) warnings: Vec<String>,
+}
+
+/// How a plugstack value is read: strings as is, the rest as TOML (bool,
+/// number, array), left to the config deserialization to reject.
+#[derive(Clone, Copy, PartialEq)]
+enum ArgKind {
+    Str,
+    Toml,
+}
+
+// Keys of the main configuration that can be set on the plugstack line.
+// Listed explicitly, the serialized defaults miss the keys whose default is None.
+const CONFIG_KEYS: &[(&str, ArgKind)] = &[
+    ("skybox_enabled", ArgKind::Toml),
+    ("parallax_imagestore", ArgKind::Str),
+    ("parallax_mount_program", ArgKind::Str),
+    ("parallax_path", ArgKind::Str),
+    ("parallax_mp_uid", ArgKind::Toml),
+    ("parallax_mp_gid", ArgKind::Toml),
+    ("parallax_mp_squashfuse_path", ArgKind::Str),
+    ("parallax_mp_logfile", ArgKind::Str),
+    ("podman_module", ArgKind::Str),
+    ("podman_path", ArgKind::Str),
+    ("podman_tmp_path", ArgKind::Str),
+    ("tracking_enabled", ArgKind::Toml),
+    ("tracking_tool", ArgKind::Str),
+    ("perfmon", ArgKind::Toml),
+];
+
+// Keys of the [skybox] table, without the prefix. A trailing ".*" takes any
+// key below, for maps such as the hook policies.
+const SKYBOX_KEYS: &[(&str, ArgKind)] = &[
+    ("workdir_fallback", ArgKind::Toml),
+    ("default_mounts.home", ArgKind::Toml),
+    ("default_mounts.submit_dir", ArgKind::Toml),
+    ("default_mounts.scratch_env", ArgKind::Str),
+    ("default_mounts.slurm", ArgKind::Toml),
+    ("slurm_client.paths", ArgKind::Toml),
+    ("tracking.sink", ArgKind::Str),
+    ("tracking.path", ArgKind::Str),
+    ("tracking.timeout_ms", ArgKind::Toml),
+    ("tracking.fail_policy", ArgKind::Str),
+    ("tracking.redact.keys", ArgKind::Toml),
+    ("tracking.redact.patterns", ArgKind::Toml),
+    ("tracking.redact.mask", ArgKind::Str),
+    ("log_format", ArgKind::Str),
+    ("debug_log_dir", ArgKind::Str),
+    ("command_output.max_lines", ArgKind::Toml),
+    ("command_output.max_bytes", ArgKind::Toml),
+    ("command_output.verbosity.*", ArgKind::Str),
+    ("hooks.fail_policy", ArgKind::Str),
+    ("hooks.policies.*", ArgKind::Str),
+    ("layers.partition.*", ArgKind::Toml),
+    ("layers.account.*", ArgKind::Toml),
+    ("layers.user_file", ArgKind::Str),
+    ("layers.user_keys", ArgKind::Toml),
+    ("admission.partitions", ArgKind::Toml),
+    ("admission.qos", ArgKind::Toml),
+    ("admission.accounts", ArgKind::Toml),
+    ("admission.groups", ArgKind::Toml),
+    ("constraints.mount_source_prefixes", ArgKind::Toml),
+    ("constraints.forbidden_mount_flags", ArgKind::Toml),
+    ("constraints.readonly_mount_prefixes", ArgKind::Toml),
+    ("constraints.annotations_allow", ArgKind::Toml),
+    ("constraints.annotations_deny", ArgKind::Toml),
+    ("constraints.image_registries", ArgKind::Toml),
+    ("verify_edf", ArgKind::Toml),
+    ("edf_source", ArgKind::Str),
+    ("default_edf.site", ArgKind::Str),
+    ("default_edf.partitions.*", ArgKind::Str),
+    ("default_edf.user", ArgKind::Toml),
+    ("health.enabled", ArgKind::Toml),
+    ("health.epilog", ArgKind::Toml),
+    ("health.marker_path", ArgKind::Str),
+    ("health.timeout_ms", ArgKind::Toml),
+    ("stop.grace_period_ms", ArgKind::Toml),
+    ("stop.cleanup_timeout_ms", ArgKind::Toml),
+    ("job_info.source", ArgKind::Str),
+    ("job_info.scontrol", ArgKind::Str),
+    ("job_info.timeout_ms", ArgKind::Toml),
+];
+
+fn arg_kind(keys: &[(&str, ArgKind)], path: &str) -> Option<ArgKind> {
+    keys.iter().find_map(|(k, kind)| {
+        let known = match k.strip_suffix(".*") {
+            Some(map) => path
+                .strip_prefix(map)
+                .is_some_and(|rest| rest.len() > 1 && rest.starts_with('.')),
+            None => *k == path,
+        };
+        known.then_some(*kind)
+    })
+}
+
+pub(crate) fn plugstack_args(spank: &SpankHandle) -> PlugstackArgs {
+    match spank.plugin_argv() {
+        Ok(argv) => plugstack_parse(&argv),
+        Err(_) => PlugstackArgs::default(),
+    }
+}
+
+pub(crate) fn plugstack_parse<S: AsRef<str>>(argv: &[S]) -> PlugstackArgs {
+    let mut args = PlugstackArgs::default();
+
+    for arg in argv.iter() {
+        let arg = arg.as_ref();
+
+        // Values may contain '=', only the first one separates the key.
+        let (key, value) = match arg.split_once('=') {
+            Some(kv) => kv,
+            None => {
+                args.warnings.push(format!(
+                    "ignoring plugstack argument \"{arg}\", expected key=value"
+                ));
+                continue;
+            }
+        };
+
+        match key {
+            "config_path" => args.config_path = Some(PathBuf::from(value)),
+            "enabled" => match value.parse::<bool>() {
+                Ok(b) => args.enabled = Some(b),
+                Err(_) => args
+                    .warnings
+                    .push(format!("invalid plugstack value enabled={value}")),
+            },
+            "default_edf" => args.default_edf = Some(String::from(value)),
+            "log_level" => match LogThreshold::from_name(value) {
+                Some(l) => args.log_level = Some(l),
+                None => args
+                    .warnings
+                    .push(format!("invalid plugstack value log_level={value}")),
+            },
+            "required_partitions" => {
+                args.required_partitions = value
+                    .split(',')
+                    .filter(|p| !p.is_empty())
+                    .map(String::from)
+                    .collect();
+            }
+            _ => {
+                let (keys, path) = match key.strip_prefix("skybox.") {
+                    Some(p) => (SKYBOX_KEYS, p),
+                    None => (CONFIG_KEYS, key),
+                };
+
+                let value = match arg_kind(keys, path) {
+                    Some(kind) => typed_value(kind, value),
+                    None => {
+                        args.warnings
+                            .push(format!("unknown plugstack argument \"{key}\""));
+                        continue;
+                    }
+                };
+
+                if path == key {
+                    args.config.insert(String::from(key), value);
+                } else {
+                    args.skybox.push((String::from(path), value));
+                }
+            }
+        }
+    }
+
+    args
+}
+
+fn defaults_table<T: Serialize>(value: &T) -> toml::Table {
+    match toml::Value::try_from(value) {
+        Ok(toml::Value::Table(t)) => t,
+        _ => toml::Table::new(),
+    }
+}
+
+fn typed_value(kind: ArgKind, value: &str) -> toml::Value {
+    if kind == ArgKind::Str {
+        return toml::Value::String(String::from(value));
+    }
+
+    match toml::from_str::<toml::Table>(&format!("v = {value}")) {
+        Ok(mut t) => match t.remove("v") {
+            Some(v) => v,
+            None => toml::Value::String(String::from(value)),
+        },
+        Err(_) => toml::Value::String(String::from(value)),
+    }
+}
+
+pub(crate) fn table_insert_dotted(table: &mut toml::Table, path: &str, value: toml::Value) {
+    match path.split_once('.') {
+        Some((head, rest)) => {
+            let entry = table
+                .entry(head)
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+            if !entry.is_table() {
+                *entry = toml::Value::Table(toml::Table::new());
+            }
+            if let toml::Value::Table(t) = entry {
+                table_insert_dotted(t, rest, value);
+            }
+        }
+        None => {
+            table.insert(String::from(path), value);
+        }
+    }
+}
+
+/// Apply the plugstack overrides of the main configuration.
+pub(crate) fn plugstack_apply_config(
+    args: &PlugstackArgs,
+    config: &Config,
+) -> Result<Config, Box<dyn Error>> {
+    if args.config.is_empty() && args.enabled.is_none() {
+        return Ok(config.clone());
+    }
+
+    let mut table = defaults_table(config);
+    for (key, value) in args.config.iter() {
+        table.insert(key.clone(), value.clone());
+    }
+    if let Some(enabled) = args.enabled {
+        table.insert(
+            String::from("skybox_enabled"),
+            toml::Value::Boolean(enabled),
+        );
+    }
+
+    match toml::Value::Table(table).try_into() {
+        Ok(c) => Ok(c),
+        Err(e) => Err(format!("invalid plugstack configuration override: {e}").into()),
+    }
+}
+
+/// A job submitted to several partitions ("a,b") may run on any of them.
+pub(crate) fn plugstack_partition_allowed(args: &PlugstackArgs, partition: &str) -> bool {
+    args.required_partitions.is_empty()
+        || partition
+            .split(',')
+            .all(|p| args.required_partitions.iter().any(|r| r == p))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SkyBoxConfig;
+
+    fn leaves(table: &toml::Table, prefix: &str, out: &mut Vec<String>) {
+        for (key, value) in table.iter() {
+            let path = match prefix.is_empty() {
+                true => key.clone(),
+                false => format!("{prefix}.{key}"),
+            };
+            match value {
+                toml::Value::Table(t) => leaves(t, &path, out),
+                _ => out.push(path),
+            }
+        }
+    }
+
+    #[test]
+    fn known_keys_match_config() {
+        let mut config = vec![];
+        leaves(&defaults_table(&Config::default()), "", &mut config);
+        for key in config.iter() {
+            assert!(arg_kind(CONFIG_KEYS, key).is_some(), "{key}");
+        }
+
+        let mut skybox = vec![];
+        leaves(&defaults_table(&SkyBoxConfig::default()), "", &mut skybox);
+        for key in skybox.iter() {
+            assert!(arg_kind(SKYBOX_KEYS, key).is_some(), "skybox.{key}");
+        }
+        for (key, _) in SKYBOX_KEYS.iter().filter(|(k, _)| !k.ends_with(".*")) {
+            assert!(skybox.iter().any(|k| k == key), "skybox.{key}");
+        }
+    }
+
+    #[test]
+    fn parse_typed_values() {
+        let args = plugstack_parse(&[
+            "parallax_mp_logfile=/var/log/mp.log",
+            "parallax_mp_uid=0",
+            "skybox.stop.grace_period_ms=5000",
+            "skybox.hooks.policies.pre_start=fail",
+            "skybox.hooks.policies=fail",
+            "skybox.nonexistent=1",
+        ]);
+
+        assert_eq!(
+            args.config.get("parallax_mp_logfile"),
+            Some(&toml::Value::String(String::from("/var/log/mp.log")))
+        );
+        assert_eq!(
+            args.config.get("parallax_mp_uid"),
+            Some(&toml::Value::Integer(0))
+        );
+        assert_eq!(
+            args.skybox,
+            vec![
+                (
+                    String::from("stop.grace_period_ms"),
+                    toml::Value::Integer(5000)
+                ),
+                (
+                    String::from("hooks.policies.pre_start"),
+                    toml::Value::String(String::from("fail"))
+                ),
+            ]
+        );
+        assert_eq!(
+            args.warnings,
+            vec![
+                "unknown plugstack argument \"skybox.hooks.policies\"",
+                "unknown plugstack argument \"skybox.nonexistent\"",
+            ]
+        );
+    }
+}
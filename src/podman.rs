@@ -1,29 +1,80 @@
 use std::error::Error;
-use std::path::PathBuf;
-use std::time::Instant;
-use sysinfo::{Pid, System};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use nix::errno::Errno;
+use nix::sys::signal::{Signal, kill};
+use nix::unistd::Pid;
 
 use slurm_spank::{SpankHandle, spank_log_user};
 
-use sarus_suite_podman_driver::loggable::{self as pmd, ExecutedCommand};
+use sarus_suite_podman_driver::loggable as pmd;
 use sarus_suite_podman_driver::{ContainerCtx, PodmanCtx};
 
-use crate::{SpankSkyBox, plugin_err, skybox_log_debug};
-use crate::config::setup_imagestore;
+use crate::hooks::{HookPoint, hook_point_run};
+use crate::perf::{perf_share, perf_share_image_cache};
+use crate::{Run, SpankSkyBox, plugin_err, skybox_log_debug, skybox_log_error, skybox_log_info, spank_getenv};
+use crate::config::{CommandVerbosity, setup_imagestore};
+use crate::report::{command_forked, report_command};
+
+// After SIGKILL only the kernel is left to act.
+const STOP_KILL_WAIT: Duration = Duration::from_secs(1);
+
+// Signal 0 only checks the process is there. The container process is a
+// child of conmon, which reaps it as soon as it exits.
+fn process_exists(pid: Pid) -> bool {
+    kill(pid, None) != Err(Errno::ESRCH)
+}
 
-fn process_exists(pid: usize) -> bool {
-    let p = Pid::from(pid);
+// Poll until the process is gone, true if it went away within `grace`.
+fn process_wait_exit(pid: Pid, grace: Duration) -> bool {
+    let t0 = Instant::now();
+    let pause = Duration::from_millis(100);
 
-    let s = System::new_all();
-    let ret = match s.process(p) {
-        None => false,
-        Some(process) => {
-            let state = process.status();
-            skybox_log_debug!("process {pid} status is {state}");
-            true
+    while process_exists(pid) {
+        if t0.elapsed() >= grace {
+            return false;
         }
-    };
-    ret
+        std::thread::sleep(pause);
+    }
+    true
+}
+
+fn process_signal(pid: Pid, signal: Signal) {
+    match kill(pid, signal) {
+        Ok(_) => skybox_log_debug!("sent {signal} to process {pid}"),
+        Err(Errno::ESRCH) => (),
+        Err(e) => skybox_log_error!("cannot send {signal} to process {pid}: {e}"),
+    }
+}
+
+/// Podman context of the step container, the same for run, stop and rm.
+fn podman_run_ctx(config: &raster::Config, run: &Run) -> PodmanCtx {
+    let graphroot = format!("{}/graphroot", run.podman_tmp_path);
+    let runroot = format!("{}/runroot", run.podman_tmp_path);
+
+    podman_storage_ctx(config, &graphroot, &runroot)
+}
+
+/// Podman context of the job containers on the given storage.
+pub(crate) fn podman_storage_ctx(
+    config: &raster::Config,
+    graphroot: &str,
+    runroot: &str,
+) -> PodmanCtx {
+    PodmanCtx {
+        podman_path: PathBuf::from(&config.podman_path),
+        module: Some(String::from(&config.podman_module)),
+        graphroot: Some(PathBuf::from(graphroot)),
+        runroot: Some(PathBuf::from(runroot)),
+        parallax_mount_program: Some(PathBuf::from(&config.parallax_mount_program)),
+        ro_store: Some(PathBuf::from(&config.parallax_imagestore)),
+        podman_env: None,
+    }
+    .with_env("PARALLAX_MP_UID", config.parallax_mp_uid.to_string())
+    .with_env("PARALLAX_MP_GID", config.parallax_mp_gid.to_string())
+    .with_env("PARALLAX_MP_SQUASHFUSE_CMD", config.parallax_mp_squashfuse_path.clone())
+    .with_env("PARALLAX_MP_LOGFILE", config.parallax_mp_logfile.clone())
 }
 
 pub(crate) fn podman_pull(
@@ -46,6 +97,7 @@ pub(crate) fn podman_pull(
 
     let config = &ssb.config;
     setup_imagestore(config)?;
+    hook_point_run(ssb, HookPoint::PrePull, None)?;
 
     let graphroot = format!("{}/graphroot", run.podman_tmp_path);
     let runroot = format!("{}/runroot", run.podman_tmp_path);
@@ -93,7 +145,10 @@ pub(crate) fn podman_pull(
     .with_env("PARALLAX_MP_SQUASHFUSE_CMD", config.parallax_mp_squashfuse_path.clone())
     .with_env("PARALLAX_MP_LOGFILE", config.parallax_mp_logfile.clone());
 
-    if !pmd_image_exists(&edf.image, &ro_ctx) {
+    let cache_hit = pmd_image_exists(&edf.image, &ro_ctx);
+    perf_share_image_cache(ssb, cache_hit);
+
+    if !cache_hit {
         skybox_log_debug!(
             "pulling image \"{}\" from remote in local graphroot",
             edf.image
@@ -113,6 +168,8 @@ pub(crate) fn podman_pull(
         if !pmd_image_exists(&edf.image, &ro_ctx) {
             return plugin_err("couldn't find image on shared imagestore after migration");
         }
+
+        hook_point_run(ssb, HookPoint::PostMigrate, None)?;
     }
 
     Ok(())
@@ -138,8 +195,6 @@ pub(crate) fn podman_start(
 
     let config = &ssb.config;
 
-    let graphroot = format!("{}/graphroot", run.podman_tmp_path);
-    let runroot = format!("{}/runroot", run.podman_tmp_path);
     let pidfile = format!("{}/pidfile", run.podman_tmp_path);
     //let command = vec!["sleep", "infinity"];
     let command = vec!["sh", "-c", "kill -STOP $$ ; exit 0"];
@@ -153,26 +208,20 @@ pub(crate) fn podman_start(
         pidfile: Some(PathBuf::from(pidfile.clone())),
     };
 
-    let run_ctx = PodmanCtx {
-        podman_path: PathBuf::from(&config.podman_path),
-        module: Some(String::from(&config.podman_module)),
-        graphroot: Some(PathBuf::from(&graphroot)),
-        runroot: Some(PathBuf::from(&runroot)),
-        parallax_mount_program: Some(PathBuf::from(&config.parallax_mount_program)),
-        ro_store: Some(PathBuf::from(&config.parallax_imagestore)),
-        podman_env: None,
-    }
-    .with_env("PARALLAX_MP_UID", config.parallax_mp_uid.to_string())
-    .with_env("PARALLAX_MP_GID", config.parallax_mp_gid.to_string())
-    .with_env("PARALLAX_MP_SQUASHFUSE_CMD", config.parallax_mp_squashfuse_path.clone())
-    .with_env("PARALLAX_MP_LOGFILE", config.parallax_mp_logfile.clone());
+    let run_ctx = podman_run_ctx(config, run);
 
     skybox_log_debug!("mount env: PARALLAX_MP_UID={} PARALLAX_MP_GID={}", config.parallax_mp_uid.to_string(), config.parallax_mp_gid.to_string());
 
-    return pmd_run(&edf, &config, &run_ctx, &c_ctx, command);
+    hook_point_run(ssb, HookPoint::PreStart, None)?;
+
+    let t0 = Instant::now();
+    let result = pmd_run(edf, config, &run_ctx, &c_ctx, command);
+    perf_share(ssb, "podman_run", t0.elapsed());
+
+    result
 }
 
-pub(crate) fn podman_get_pid_from_file(ssb: &mut SpankSkyBox) -> Result<usize, Box<dyn Error>> {
+pub(crate) fn podman_get_pid_from_file(ssb: &mut SpankSkyBox) -> Result<Pid, Box<dyn Error>> {
     let run = match &ssb.run {
         Some(o) => o,
         None => {
@@ -182,72 +231,157 @@ pub(crate) fn podman_get_pid_from_file(ssb: &mut SpankSkyBox) -> Result<usize, B
 
     //Try to read from pidfile
     let pidfile = format!("{}/pidfile", run.podman_tmp_path);
-    if std::path::Path::new(&pidfile).exists() {
-        let strpid = match std::fs::read_to_string(&pidfile) {
+    pid_read(&pidfile)
+}
+
+fn pid_read(pidfile: &str) -> Result<Pid, Box<dyn Error>> {
+    if std::path::Path::new(pidfile).exists() {
+        let strpid = match std::fs::read_to_string(pidfile) {
             Ok(s) => s,
             Err(_) => {
                 let err_msg = format!("cannot read pid from {pidfile}");
                 return Err(err_msg.into());
             }
         };
-        let pid: usize = match strpid.parse() {
-            Ok(p) => p,
-            Err(_) => {
-                let err_msg = format!("cannot convert {strpid} to number");
-                return Err(err_msg.into());
+        match pid_parse(&strpid) {
+            Some(p) => Ok(p),
+            None => {
+                let err_msg = format!("{pidfile} has no valid pid: {:?}", strpid.trim());
+                Err(err_msg.into())
             }
-        };
-        return Ok(pid);
+        }
     } else {
         let err_msg = format!("{pidfile} NOT FOUND!");
         Err(err_msg.into())
     }
 }
 
+/// Pid written by podman in the pidfile of the container.
+///
+/// The pidfile lives in a directory of the job user, root must not trust it
+/// further than a positive pid: 0, 1 and negative pids would signal a process
+/// group, init or every process of the node.
+pub(crate) fn pid_parse(s: &str) -> Option<Pid> {
+    let pid: i64 = s.trim().parse().ok()?;
+    match i32::try_from(pid) {
+        Ok(p) if p > 1 => Some(Pid::from_raw(p)),
+        _ => None,
+    }
+}
+
+// Real uid of a process, from the Uid line of its status.
+fn process_uid(pid: Pid) -> Option<u32> {
+    let status = std::fs::read_to_string(format!("/proc/{pid}/status")).ok()?;
+    let line = status.lines().find_map(|l| l.strip_prefix("Uid:"))?;
+    line.split_whitespace().next()?.parse().ok()
+}
+
+/// Stop the step container once the last local task is done.
+///
+/// The entrypoint stopped itself after setup, SIGCONT lets it exit. If it is
+/// still there after the grace period it gets SIGTERM, then SIGKILL. Podman
+/// then stops and removes the container so no conmon is left behind, and the
+/// container state in runroot is dropped.
 pub(crate) fn podman_stop(
     ssb: &mut SpankSkyBox,
-    _spank: &mut SpankHandle,
+    spank: &mut SpankHandle,
 ) -> Result<(), Box<dyn Error>> {
-    let run = match &ssb.run {
-        Some(o) => o,
-        None => {
+    let (run, job) = match (&ssb.run, &ssb.job) {
+        (Some(r), Some(j)) => (r, j),
+        _ => {
             return plugin_err("couldn't find run data");
         }
     };
 
-    let pid = run.pid;
-
-    skybox_log_debug!("stopping container, process {pid}");
-    let mut kill = std::process::Command::new("kill")
-        .args(["-s", "SIGCONT", &pid.to_string()])
-        .spawn()?;
-    kill.wait()?;
+    let stop = &ssb.skybox_config.stop;
+    let grace = Duration::from_millis(stop.grace_period_ms);
+
+    // The pid comes from a file of the job user, only their processes get
+    // signalled.
+    let pid = run.pid.filter(|pid| process_uid(*pid) == Some(job.uid));
+    if let Some(pid) = pid {
+        // SIGCONT and SIGTERM share the grace period, SIGKILL gets a short wait.
+        skybox_log_debug!("stopping container {}, process {pid}", run.name);
+        process_signal(pid, Signal::SIGCONT);
+
+        if !process_wait_exit(pid, grace / 2) {
+            skybox_log_debug!("process {pid} is still there, terminating it.");
+            process_signal(pid, Signal::SIGTERM);
+
+            if !process_wait_exit(pid, grace - grace / 2) {
+                skybox_log_info!("process {pid} ignored SIGTERM, killing it.");
+                process_signal(pid, Signal::SIGKILL);
+                process_wait_exit(pid, STOP_KILL_WAIT);
+            }
+        }
+    } else if let Some(pid) = run.pid
+        && process_exists(pid)
+    {
+        skybox_log_error!(
+            "process {pid} of container {} isn't owned by uid {}, not signalling it",
+            run.name,
+            job.uid
+        );
+    } else {
+        skybox_log_debug!("no process for container {}, cleaning up only", run.name);
+    }
 
-    if process_exists(pid) {
-        skybox_log_debug!("process {pid} is still there, waiting one more second.");
-        let pause = std::time::Duration::from_secs(1);
-        std::thread::sleep(pause);
+    // The storage is the rootless one of the job user, podman runs as them
+    // with the environment the container was started with.
+    let user = Some((job.uid, job.gid));
+    let mut env = vec![];
+    for key in ["HOME", "XDG_RUNTIME_DIR"] {
+        let value = spank_getenv(spank, key);
+        if !value.is_empty() {
+            env.push((String::from(key), value));
+        }
     }
 
-    if process_exists(pid) {
-        skybox_log_debug!("process {pid} is still there, terminating it.");
-        let mut kill = std::process::Command::new("kill")
-            .args(["-s", "SIGTERM", &pid.to_string()])
-            .spawn()?;
-        kill.wait()?;
+    let ctx = podman_run_ctx(&ssb.config, run);
+    let deadline = Instant::now() + Duration::from_millis(stop.cleanup_timeout_ms);
+    pmd_forked("podman stop", user, &env, deadline, || {
+        pmd::stop(&run.name, Some(&ctx))
+    });
+    pmd_forked("podman rm", user, &env, deadline, || {
+        pmd::rm(&run.name, Some(&ctx))
+    });
+
+    let runroot = format!("{}/runroot", run.podman_tmp_path);
+    if Path::new(&runroot).exists() && let Err(e) = std::fs::remove_dir_all(&runroot) {
+        skybox_log_error!("couldn't cleanup {runroot}: {e}");
     }
 
     Ok(())
 }
 
+// The container process is normally gone by now, stop mostly lets conmon go.
+fn pmd_forked<F>(
+    prefix: &str,
+    user: Option<(u32, u32)>,
+    env: &[(String, String)],
+    deadline: Instant,
+    call: F,
+) where
+    F: FnOnce() -> raster::ExecutedCommand,
+{
+    let t0 = Instant::now();
+    let left = deadline.saturating_duration_since(t0);
+
+    match command_forked(user, env, left, call) {
+        Ok(ec) => report_command(&ec.command, &ec.output, prefix, CommandVerbosity::Debug, t0.elapsed()),
+        Err(e) => skybox_log_error!("{prefix}: {e}"),
+    }
+}
+
 pub(crate) fn pmd_image_exists(image: &str, ctx: &PodmanCtx) -> bool {
     let prefix = "podman image exists";
 
+    let t0 = Instant::now();
     let ec = pmd::image_exists(&image, Some(&ctx));
 
     let result = ec.output.status.success();
 
-    log_ec(ec, prefix);
+    report_command(&ec.command, &ec.output, prefix, CommandVerbosity::Debug, t0.elapsed());
 
     result
 }
@@ -255,9 +389,10 @@ pub(crate) fn pmd_image_exists(image: &str, ctx: &PodmanCtx) -> bool {
 pub(crate) fn pmd_pull(image: &str, ctx: &PodmanCtx) -> () {
     let prefix = "podman pull";
 
+    let t0 = Instant::now();
     let ec = pmd::pull(&image, Some(&ctx));
 
-    log_ec(ec, prefix);
+    report_command(&ec.command, &ec.output, prefix, CommandVerbosity::Error, t0.elapsed());
 }
 
 pub(crate) fn pmd_parallax_migrate(
@@ -267,9 +402,10 @@ pub(crate) fn pmd_parallax_migrate(
 ) -> Result<(), Box<dyn Error>> {
     let prefix = "parallax_migrate";
 
+    let t0 = Instant::now();
     let ec = pmd::parallax_migrate(&PathBuf::from(parallax_path), ctx, image);
 
-    log_ec(ec.clone(), prefix);
+    report_command(&ec.command, &ec.output, prefix, CommandVerbosity::Error, t0.elapsed());
 
     match ec.output.status.code() {
         Some(rc) => {
@@ -286,9 +422,10 @@ pub(crate) fn pmd_parallax_migrate(
 pub(crate) fn pmd_rmi(image: &str, ctx: &PodmanCtx) -> () {
     let prefix = "podman rmi";
 
+    let t0 = Instant::now();
     let ec = pmd::rmi(&image, Some(&ctx));
 
-    log_ec(ec, prefix);
+    report_command(&ec.command, &ec.output, prefix, CommandVerbosity::Debug, t0.elapsed());
 }
 
 pub(crate) fn pmd_run<I, S>(
@@ -315,7 +452,7 @@ where
         );
     }
 
-    log_ec(ec.clone(), prefix);
+    report_command(&ec.command, &ec.output, prefix, CommandVerbosity::Error, tend);
 
     match ec.output.status.code() {
         Some(rc) => {
@@ -329,45 +466,34 @@ where
     Ok(())
 }
 
-pub(crate) fn log_ec(ec: ExecutedCommand, prefix: &str) {
-    let rc = match ec.output.status.code() {
-        Some(ok) => format!("{ok}"),
-        None => {
-            skybox_log_debug!("{prefix} exited by signal");
-            String::from("UNKNOWN")
-        }
-    };
-
-    let mut stdout = match String::from_utf8(ec.output.stdout) {
-        Ok(ok) => ok,
-        Err(_) => String::from(""),
-    };
-    if stdout.ends_with("\n") {
-        stdout.pop();
-    };
-
-    let mut stderr = match String::from_utf8(ec.output.stderr) {
-        Ok(ok) => ok,
-        Err(_) => String::from(""),
-    };
-    if stderr.ends_with("\n") {
-        stderr.pop();
-    };
-
-    skybox_log_debug!("CMD: {}", ec.command);
-    skybox_log_debug!("{prefix} exit code: {}", rc);
+#[cfg(test)]
+mod tests {
+    use super::{Pid, pid_parse, pid_read, process_uid};
+    use crate::TestDir;
 
-    if stdout != "" {
-        let lines = stdout.split("\n");
-        for line in lines {
-            skybox_log_debug!("{prefix} stdout: {}", line);
+    #[test]
+    fn parse_pid() {
+        assert_eq!(pid_parse("1234\n"), Some(Pid::from_raw(1234)));
+        for garbage in ["", "abc", "12 34", "-1", "0", "1", "4294967295", "99999999999"] {
+            assert_eq!(pid_parse(garbage), None, "{garbage:?}");
         }
     }
 
-    if stderr != "" {
-        let lines = stderr.split("\n");
-        for line in lines {
-            skybox_log_debug!("{prefix} stderr: {}", line);
-        }
+    #[test]
+    fn garbage_pidfile() {
+        let dir = TestDir::new("podman-pidfile");
+        dir.write("pidfile", "not a pid\n");
+        assert!(pid_read(&dir.path("pidfile")).is_err());
+        dir.write("pidfile", "-1\n");
+        assert!(pid_read(&dir.path("pidfile")).is_err());
+        assert!(pid_read(&dir.path("missing")).is_err());
+        dir.write("pidfile", "4321\n");
+        assert_eq!(pid_read(&dir.path("pidfile")).unwrap(), Pid::from_raw(4321));
+    }
+
+    #[test]
+    fn own_process_uid() {
+        let pid = Pid::from_raw(std::process::id() as i32);
+        assert_eq!(process_uid(pid), Some(nix::unistd::getuid().as_raw()));
     }
 }
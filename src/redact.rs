@@ -0,0 +1,270 @@
+use regex::Regex;
+use std::error::Error;
+
+use crate::config::RedactConfig;
+
+/// Masks secrets in data leaving the node, e.g. tracking records.
+pub(crate) struct Redactor {
+    keys: Vec<String>,
+    patterns: Vec<Regex>,
+    mask: String,
+}
+
+impl Redactor {
+    pub(crate) fn new(config: &RedactConfig) -> Result<Redactor, Box<dyn Error>> {
+        let mut patterns = vec![];
+        for p in config.patterns.iter() {
+            patterns.push(Regex::new(p)?);
+        }
+
+        Ok(Redactor {
+            keys: config.keys.iter().map(|k| k.to_uppercase()).collect(),
+            patterns,
+            mask: config.mask.clone(),
+        })
+    }
+
+    pub(crate) fn is_secret_key(&self, key: &str) -> bool {
+        let key = key.to_uppercase();
+        self.keys.iter().any(|g| glob_match(g, &key))
+    }
+
+    /// Mask every match of the configured patterns.
+    pub(crate) fn redact_str(&self, value: &str) -> String {
+        let mut out = String::from(value);
+        for re in self.patterns.iter() {
+            out = re.replace_all(&out, self.mask.as_str()).into_owned();
+        }
+        out
+    }
+
+    /// Mask "KEY=VALUE" arguments with a secret key, then apply the patterns.
+    pub(crate) fn redact_arg(&self, arg: &str) -> String {
+        if let Some((key, _)) = arg.split_once('=')
+            && self.is_secret_key(&flag_name(key))
+        {
+            return format!("{key}={}", self.mask);
+        }
+        self.redact_str(arg)
+    }
+
+    /// Mask a command line: single arguments as in `redact_arg`, and the
+    /// value following a secret flag given separately ("--password secret").
+    pub(crate) fn redact_argv(&self, argv: &[String]) -> Vec<String> {
+        let mut out = vec![];
+        let mut mask_next = false;
+
+        for arg in argv.iter() {
+            if mask_next {
+                out.push(self.mask.clone());
+                mask_next = false;
+                continue;
+            }
+
+            if arg.starts_with('-') && !arg.contains('=') && self.is_secret_key(&flag_name(arg)) {
+                mask_next = true;
+            }
+            out.push(self.redact_arg(arg));
+        }
+
+        out
+    }
+
+    /// Mask values of secret keys at any depth of a TOML document (EDF env,
+    /// annotations, ...). Content that doesn't parse only gets the patterns.
+    pub(crate) fn redact_toml(&self, content: &str) -> String {
+        let mut table: toml::Table = match toml::from_str(content) {
+            Ok(t) => t,
+            Err(_) => return self.redact_str(content),
+        };
+
+        for (key, value) in table.iter_mut() {
+            self.redact_toml_value(key, value);
+        }
+
+        match toml::to_string(&table) {
+            Ok(s) => s,
+            Err(_) => self.redact_str(content),
+        }
+    }
+
+    fn redact_toml_value(&self, key: &str, value: &mut toml::Value) {
+        match value {
+            toml::Value::String(s) => {
+                if self.is_secret_key(key) {
+                    *s = self.mask.clone();
+                } else {
+                    *s = self.redact_str(s);
+                }
+            }
+            toml::Value::Array(a) => {
+                for v in a.iter_mut() {
+                    self.redact_toml_value(key, v);
+                }
+            }
+            toml::Value::Table(t) => {
+                for (k, v) in t.iter_mut() {
+                    self.redact_toml_value(k, v);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+// "--api-key" is checked like the variable API_KEY.
+fn flag_name(arg: &str) -> String {
+    arg.trim_start_matches('-').replace('-', "_")
+}
+
+/// Shell style wildcard match, '*' is any sequence and '?' any character.
+pub(crate) fn glob_match(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = text.chars().collect();
+
+    let (mut pi, mut ti) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ti));
+            pi += 1;
+        } else if let Some((sp, st)) = star {
+            pi = sp + 1;
+            ti = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+
+    while pi < p.len() && p[pi] == '*' {
+        pi += 1;
+    }
+
+    pi == p.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redactor(patterns: &[&str]) -> Redactor {
+        let config = RedactConfig {
+            patterns: patterns.iter().map(|p| String::from(*p)).collect(),
+            ..RedactConfig::default()
+        };
+        Redactor::new(&config).unwrap()
+    }
+
+    fn argv(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| String::from(*a)).collect()
+    }
+
+    #[test]
+    fn glob() {
+        assert!(glob_match("*TOKEN*", "GITHUB_TOKEN"));
+        assert!(glob_match("*TOKEN*", "TOKEN"));
+        assert!(glob_match("A?C", "ABC"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("com.hooks.*", "com.hooks.ldcache.enabled"));
+        assert!(!glob_match("*TOKEN*", "TOKE"));
+        assert!(!glob_match("A?C", "AC"));
+        assert!(!glob_match("com.hooks.*", "com.hook"));
+    }
+
+    #[test]
+    fn secret_keys() {
+        let r = redactor(&[]);
+        assert!(r.is_secret_key("my_password"));
+        assert!(r.is_secret_key("Api_Key"));
+        assert!(!r.is_secret_key("HOME"));
+    }
+
+    #[test]
+    fn patterns() {
+        let r = redactor(&["ghp_[A-Za-z0-9]+", "pass:[^ ]+"]);
+        assert_eq!(r.redact_str("token ghp_abc123 end"), "token *** end");
+        assert_eq!(r.redact_str("user pass:hunter2 x"), "user *** x");
+        assert_eq!(r.redact_str("nothing here"), "nothing here");
+    }
+
+    #[test]
+    fn invalid_pattern() {
+        let config = RedactConfig {
+            patterns: vec![String::from("(")],
+            ..RedactConfig::default()
+        };
+        assert!(Redactor::new(&config).is_err());
+    }
+
+    #[test]
+    fn toml_env_and_annotations() {
+        let r = redactor(&["ghp_[a-z0-9]+"]);
+        let edf = r#"
+image = "ubuntu:24.04"
+
+[env]
+GITHUB_TOKEN = "abc"
+HOME = "/users/me"
+NOTE = "uses ghp_abc1"
+
+[annotations]
+"com.example.password" = "hunter2"
+"com.example.mode" = "fast"
+"#;
+        let table: toml::Table = toml::from_str(&r.redact_toml(edf)).unwrap();
+        assert_eq!(table["image"].as_str(), Some("ubuntu:24.04"));
+        assert_eq!(table["env"]["GITHUB_TOKEN"].as_str(), Some("***"));
+        assert_eq!(table["env"]["HOME"].as_str(), Some("/users/me"));
+        assert_eq!(table["env"]["NOTE"].as_str(), Some("uses ***"));
+        assert_eq!(
+            table["annotations"]["com.example.password"].as_str(),
+            Some("***")
+        );
+        assert_eq!(
+            table["annotations"]["com.example.mode"].as_str(),
+            Some("fast")
+        );
+    }
+
+    #[test]
+    fn toml_unparsable() {
+        let r = redactor(&["secret[0-9]"]);
+        assert_eq!(r.redact_toml("not = = toml secret1"), "not = = toml ***");
+    }
+
+    #[test]
+    fn arg_key_value() {
+        let r = redactor(&[]);
+        assert_eq!(r.redact_arg("MY_TOKEN=abc"), "MY_TOKEN=***");
+        assert_eq!(r.redact_arg("--password=abc"), "--password=***");
+        assert_eq!(r.redact_arg("--api-key=abc"), "--api-key=***");
+        assert_eq!(r.redact_arg("--output=abc"), "--output=abc");
+        assert_eq!(r.redact_arg("plain"), "plain");
+    }
+
+    #[test]
+    fn argv_separate_value() {
+        let r = redactor(&[]);
+        assert_eq!(
+            r.redact_argv(&argv(&["app", "--password", "hunter2", "-n", "4"])),
+            argv(&["app", "--password", "***", "-n", "4"])
+        );
+        assert_eq!(
+            r.redact_argv(&argv(&["app", "--api-key", "k", "--token=t", "x"])),
+            argv(&["app", "--api-key", "***", "--token=***", "x"])
+        );
+        assert_eq!(
+            r.redact_argv(&argv(&["app", "--password"])),
+            argv(&["app", "--password"])
+        );
+        assert_eq!(
+            r.redact_argv(&argv(&["echo", "password", "x"])),
+            argv(&["echo", "password", "x"])
+        );
+    }
+}
@@ -0,0 +1,301 @@
+use std::error::Error;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::Read;
+use std::os::fd::OwnedFd;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::sync::{Mutex, mpsc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nix::fcntl::OFlag;
+use nix::sys::signal::{Signal, kill, killpg};
+use nix::sys::wait::waitpid;
+use nix::unistd::{ForkResult, Gid, Pid, Uid, fork, pipe2, setgid, setgroups, setpgid, setuid};
+use raster::ExecutedCommand;
+use serde::{Deserialize, Serialize};
+use slurm_spank::{spank_log_error, spank_log_user};
+
+use crate::config::{CommandOutputConfig, CommandVerbosity};
+use crate::{plugin_string, skybox_log_debug, skybox_log_error};
+
+// Set once the skybox configuration is loaded, defaults apply until then.
+static REPORT_CONFIG: Mutex<Option<CommandOutputConfig>> = Mutex::new(None);
+
+pub(crate) fn report_configure(config: &CommandOutputConfig) {
+    if let Ok(mut c) = REPORT_CONFIG.lock() {
+        *c = Some(config.clone());
+    }
+}
+
+/// Report the result of an external command (podman driver call, raster hook).
+///
+/// Everything goes to the debug log. Depending on the verbosity, output is
+/// also echoed to the user, or to the error log when the command failed.
+/// `verbosity` is the call site default, the site can override it by prefix.
+pub(crate) fn report_command<C: Display>(
+    command: C,
+    output: &Output,
+    prefix: &str,
+    verbosity: CommandVerbosity,
+    elapsed: Duration,
+) {
+    let config = match REPORT_CONFIG.lock() {
+        Ok(c) => c.clone().unwrap_or_default(),
+        Err(_) => CommandOutputConfig::default(),
+    };
+    let verbosity = *config.verbosity.get(prefix).unwrap_or(&verbosity);
+
+    let rc = match output.status.code() {
+        Some(ok) => format!("{ok}"),
+        None => {
+            skybox_log_debug!("{prefix} exited by signal");
+            String::from("UNKNOWN")
+        }
+    };
+    let failed = !output.status.success();
+
+    skybox_log_debug!("CMD: {}", command);
+    skybox_log_debug!(
+        "{prefix} exit code: {} elapsed: {:.3} sec",
+        rc,
+        elapsed.as_secs_f64()
+    );
+
+    let stdout = capped_lines(&output.stdout, &config);
+    let stderr = capped_lines(&output.stderr, &config);
+
+    for line in stdout.iter() {
+        skybox_log_debug!("{prefix} stdout: {}", line);
+        if verbosity == CommandVerbosity::User {
+            spank_log_user!("{}", line);
+        }
+    }
+
+    for line in stderr.iter() {
+        skybox_log_debug!("{prefix} stderr: {}", line);
+        match verbosity {
+            CommandVerbosity::User => spank_log_error!("{}", line),
+            CommandVerbosity::Error if failed => skybox_log_error!("{prefix}: {}", line),
+            _ => (),
+        }
+    }
+}
+
+/// Run a command to completion, killing it after `timeout`. Output is read
+/// while it runs so a chatty command cannot block on a full pipe.
+pub(crate) fn command_output_timeout(
+    command: &mut Command,
+    timeout: Duration,
+) -> Result<Output, Box<dyn Error>> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let (tx, rx) = mpsc::channel();
+    let readers = [
+        child
+            .stdout
+            .take()
+            .map(|p| Box::new(p) as Box<dyn Read + Send>),
+        child
+            .stderr
+            .take()
+            .map(|p| Box::new(p) as Box<dyn Read + Send>),
+    ];
+    for (i, pipe) in readers.into_iter().enumerate() {
+        let tx = tx.clone();
+        thread::spawn(move || {
+            let mut buf = vec![];
+            if let Some(mut p) = pipe {
+                let _ = p.read_to_end(&mut buf);
+            }
+            let _ = tx.send((i, buf));
+        });
+    }
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            let msg = format!("{:?} timed out after {} ms", command, timeout.as_millis());
+            return Err(plugin_string(&msg).into());
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    // Children of the command may keep the pipes open, don't wait past the deadline.
+    let mut output = Output {
+        status,
+        stdout: vec![],
+        stderr: vec![],
+    };
+    for _ in 0..2 {
+        let left = deadline.saturating_duration_since(Instant::now());
+        match rx.recv_timeout(left) {
+            Ok((0, buf)) => output.stdout = buf,
+            Ok((_, buf)) => output.stderr = buf,
+            Err(_) => break,
+        }
+    }
+
+    Ok(output)
+}
+
+/// A podman driver call run by [`command_forked`].
+pub(crate) struct ForkedCommand {
+    pub(crate) command: String,
+    pub(crate) output: Output,
+}
+
+// What the child hands back to the parent through the pipe.
+#[derive(Serialize, Deserialize)]
+struct ForkedOutput {
+    command: String,
+    status: i32,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+/// Run `call`, a podman driver call, in a forked child: as `user` (uid, gid)
+/// when given, with `env` added. The driver spawns podman itself, so this is
+/// how its calls get a timeout and run against rootless storage from root.
+/// The child and everything it started are killed after `timeout`.
+pub(crate) fn command_forked<F>(
+    user: Option<(u32, u32)>,
+    env: &[(String, String)],
+    timeout: Duration,
+    call: F,
+) -> Result<ForkedCommand, Box<dyn Error>>
+where
+    F: FnOnce() -> ExecutedCommand,
+{
+    let (rd, wr) = pipe2(OFlag::O_CLOEXEC)?;
+
+    match unsafe { fork() }? {
+        ForkResult::Child => {
+            drop(rd);
+            let rc = forked_child(user, env, call, wr);
+            unsafe { libc::_exit(rc) }
+        }
+        ForkResult::Parent { child } => {
+            drop(wr);
+            // Also done by the child, whichever comes first.
+            let _ = setpgid(child, child);
+            forked_parent(child, rd, timeout)
+        }
+    }
+}
+
+fn forked_child<F>(user: Option<(u32, u32)>, env: &[(String, String)], call: F, wr: OwnedFd) -> i32
+where
+    F: FnOnce() -> ExecutedCommand,
+{
+    let _ = setpgid(Pid::from_raw(0), Pid::from_raw(0));
+
+    if let Some((uid, gid)) = user {
+        let gid = Gid::from_raw(gid);
+        if setgroups(&[gid]).is_err() || setgid(gid).is_err() || setuid(Uid::from_raw(uid)).is_err()
+        {
+            return 126;
+        }
+    }
+
+    for (k, v) in env {
+        unsafe {
+            std::env::set_var(k, v);
+        }
+    }
+
+    let ec = call();
+    let out = ForkedOutput {
+        command: ec.command.to_string(),
+        status: ec.output.status.into_raw(),
+        stdout: ec.output.stdout,
+        stderr: ec.output.stderr,
+    };
+
+    match serde_json::to_writer(File::from(wr), &out) {
+        Ok(_) => 0,
+        Err(_) => 1,
+    }
+}
+
+fn forked_parent(
+    child: Pid,
+    rd: OwnedFd,
+    timeout: Duration,
+) -> Result<ForkedCommand, Box<dyn Error>> {
+    // The pipe is close-on-exec, only the child itself holds it open.
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = vec![];
+        let _ = File::from(rd).read_to_end(&mut buf);
+        let _ = tx.send(buf);
+    });
+
+    let buf = match rx.recv_timeout(timeout) {
+        Ok(b) => b,
+        Err(_) => {
+            let _ = killpg(child, Signal::SIGKILL);
+            let _ = kill(child, Signal::SIGKILL);
+            let _ = waitpid(child, None);
+            let msg = format!("timed out after {} ms", timeout.as_millis());
+            return Err(plugin_string(&msg).into());
+        }
+    };
+
+    let status = waitpid(child, None)?;
+    let out: ForkedOutput = match serde_json::from_slice(&buf) {
+        Ok(o) => o,
+        Err(_) => {
+            let msg = format!("child process failed: {status:?}");
+            return Err(plugin_string(&msg).into());
+        }
+    };
+
+    Ok(ForkedCommand {
+        command: out.command,
+        output: Output {
+            status: ExitStatus::from_raw(out.status),
+            stdout: out.stdout,
+            stderr: out.stderr,
+        },
+    })
+}
+
+// Decode lossily, drop the trailing newline and keep at most max_lines and
+// max_bytes, noting what was left out.
+fn capped_lines(data: &[u8], config: &CommandOutputConfig) -> Vec<String> {
+    let text = String::from_utf8_lossy(data);
+    let text = text.strip_suffix('\n').unwrap_or(&text);
+
+    if text.is_empty() {
+        return vec![];
+    }
+
+    let mut lines = vec![];
+    let mut bytes = 0;
+    let total = text.split('\n').count();
+
+    for line in text.split('\n') {
+        if lines.len() >= config.max_lines || bytes + line.len() > config.max_bytes {
+            break;
+        }
+        bytes += line.len();
+        lines.push(String::from(line));
+    }
+
+    if lines.len() < total {
+        lines.push(format!("... {} more lines truncated", total - lines.len()));
+    }
+
+    lines
+}
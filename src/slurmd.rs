@@ -2,8 +2,13 @@ use std::error::Error;
 
 use slurm_spank::SpankHandle;
 
-use crate::config::resolve_config_path;
-use crate::{SpankSkyBox, VERSION, plugin_err, skybox_log_debug, skybox_log_info};
+use crate::config::{resolve_config_path, setup_skybox_config};
+use crate::health::health_check;
+use crate::plugstack::plugstack_apply_config;
+use crate::validate::config_validate;
+use crate::{
+    SpankSkyBox, VERSION, plugin_err, skybox_log_debug, skybox_log_error, skybox_log_info,
+};
 use raster::*;
 
 fn slurmd_load_config(
@@ -13,7 +18,10 @@ fn slurmd_load_config(
     let config_path = resolve_config_path(spank);
 
     // do not fail on variable expansion -> &Some(false)
-    plugin.config = load_config_path(config_path, VarExpand::Try, &None)?;
+    let config = load_config_path(config_path, VarExpand::Try, &None)?;
+
+    setup_skybox_config(plugin, spank)?;
+    plugin.config = plugstack_apply_config(&plugin.plugstack, &config)?;
 
     if !plugin.config.skybox_enabled {
         return plugin_err("plugin is disabled");
@@ -36,9 +44,56 @@ pub(crate) fn slurmd_init(
     }
 
     skybox_log_info!("version v{}", VERSION);
+    slurmd_log_validation(plugin);
+    health_check(plugin);
+    Ok(())
+}
+
+// Epilogs run in their own process with the plugin freshly loaded.
+#[allow(unused_variables)]
+pub(crate) fn slurmd_job_epilog(
+    plugin: &mut SpankSkyBox,
+    spank: &mut SpankHandle,
+) -> Result<(), Box<dyn Error>> {
+    match slurmd_load_config(plugin, spank) {
+        Ok(_) => (),
+        Err(e) => {
+            skybox_log_debug!("{e}");
+            return Ok(());
+        }
+    }
+
+    if plugin.skybox_config.health.epilog {
+        health_check(plugin);
+    }
     Ok(())
 }
 
+fn slurmd_log_validation(plugin: &SpankSkyBox) {
+    let report = config_validate(&plugin.config, &plugin.skybox_config);
+
+    for l in report.layers.iter() {
+        skybox_log_info!("config layer: {l}");
+    }
+
+    if report.errors.is_empty() && report.warnings.is_empty() {
+        skybox_log_info!("configuration check passed");
+        return;
+    }
+
+    skybox_log_info!(
+        "configuration check: {} error(s), {} warning(s)",
+        report.errors.len(),
+        report.warnings.len()
+    );
+    for e in report.errors.iter() {
+        skybox_log_error!("config error: {e}");
+    }
+    for w in report.warnings.iter() {
+        skybox_log_info!("config warning: {w}");
+    }
+}
+
 #[allow(unused_variables)]
 pub(crate) fn slurmd_exit(
     plugin: &mut SpankSkyBox,
@@ -1,15 +1,23 @@
-use nix::unistd::{Uid, setfsuid};
+use nix::unistd::{Gid, Uid, setfsgid, setfsuid};
 use std::error::Error;
+use std::time::Instant;
 
 use slurm_spank::SpankHandle;
 
+use crate::admission::admission_enforce;
 use crate::args::*;
 use crate::config::*;
+use crate::constraints::ConstraintViolation;
 use crate::container::*;
 use crate::edf::*;
 use raster::*;
 //use crate::skybox_log_context;
+use crate::health::health_refuse_if_marked;
+use crate::hooks::{HookPoint, hook_point_run};
+use crate::logging::{debug_log_setup, log_close_debug_file};
+use crate::perf::*;
 use crate::sync::*;
+use crate::tracking::{track_perf_summary, track_task_exit};
 use crate::{
     SpankSkyBox, VERSION, cleanup_fs_local, is_skybox_enabled, job_get_info, plugin_err,
     remote_unset_env_vars, run_set_info, setup_folders, setup_privileged_folders, skybox_log_error,
@@ -45,11 +53,29 @@ pub(crate) fn slurmstepd_init_post_opt(
         return Ok(());
     }
 
+    if let Err(e) = admission_enforce(plugin, spank) {
+        skybox_log_error!("{e}");
+        return Err(e);
+    }
+
+    if plugin.args.edf.is_some()
+        && let Err(e) = health_refuse_if_marked(plugin)
+    {
+        skybox_log_error!("{e}");
+        return Err(e);
+    }
+
     let user_uid = spank.job_uid()?;
+    let user_gid = spank.job_gid()?;
+    let t0 = Instant::now();
+    let old_gid = setfsgid(Gid::from(user_gid));
     let old_uid = setfsuid(Uid::from(user_uid));
-    let _ = load_edf(plugin, spank)?;
+    let loaded = load_edf(plugin, spank);
     //update_config_by_user(&mut plugin.config, plugin.edf.clone().unwrap())?;
-    let _ = setfsuid(Uid::from(old_uid));
+    let _ = setfsgid(old_gid);
+    let _ = setfsuid(old_uid);
+    loaded?;
+    perf_add(plugin, "edf_load", t0.elapsed());
 
     if !is_skybox_enabled(plugin, spank) {
         return Ok(());
@@ -76,19 +102,29 @@ pub(crate) fn slurmstepd_user_init(
 
     //skybox_log_context(plugin);
 
+    let t0 = Instant::now();
     match render_user_job_config(plugin, spank) {
         Ok(_) => (),
         Err(e) => {
+            if e.downcast_ref::<ConstraintViolation>().is_some()
+                || e.downcast_ref::<LayerError>().is_some()
+            {
+                return Err(e);
+            }
             //do not print anything if configuration is fine, but plugin is disabled.
             return Ok(());
         }
     }
+    perf_add(plugin, "config_render", t0.elapsed());
 
     //update_edf_defaults_via_config(plugin)?;
     //update_config_by_user(&mut plugin.config, plugin.edf.clone().unwrap())?;
 
     let _ = run_set_info(plugin, spank)?;
+    debug_log_setup(plugin, spank)?;
+    config_explain(plugin);
     setup_folders(plugin, spank)?;
+    add_slurm_client_mounts(plugin, spank)?;
     modify_edf_for_sbatch(plugin, spank)?;
 
     //skybox_log_context(plugin);
@@ -107,6 +143,10 @@ pub(crate) fn slurmstepd_task_init_privileged(
     //skybox_log_verbose!("TASK_INIT_PRIVILEGED");
     setup_privileged_folders(plugin, spank)?;
 
+    // As root, like the end records, so sinks need not be writable by users.
+    task_set_info(plugin, spank)?;
+    sync_tracking(plugin, spank)?;
+
     Ok(())
 }
 
@@ -121,13 +161,19 @@ pub(crate) fn slurmstepd_task_init(
     //skybox_log_verbose!("TASK_INIT");
     let _ = task_set_info(plugin, spank)?;
 
-    sync_tracking(plugin, spank)?;
     sync_podman_pull(plugin, spank)?;
     sync_podman_start(plugin, spank)?;
+
+    let t0 = Instant::now();
     container_join(plugin, spank)?;
     container_wait_cwd(plugin, spank)?;
     container_import_env(plugin, spank)?;
     container_set_workdir(plugin, spank)?;
+    container_check_batch_interpreter(plugin, spank)?;
+    perf_share(plugin, "join", t0.elapsed());
+
+    let pid = plugin.run.as_ref().and_then(|r| r.pid);
+    hook_point_run(plugin, HookPoint::PostJoin, pid)?;
     //container_wait_entrypoint_handover(plugin, spank)?;
 
     //skybox_log_context(plugin);
@@ -149,7 +195,11 @@ pub(crate) fn slurmstepd_task_exit(
 
     //skybox_log_context(plugin);
 
+    track_task_exit(plugin, spank);
+
+    let t0 = Instant::now();
     sync_podman_stop(plugin, spank)?;
+    perf_add(plugin, "stop", t0.elapsed());
 
     Ok(())
 }
@@ -166,10 +216,25 @@ pub(crate) fn slurmstepd_exit(
 
     //skybox_log_context(plugin);
 
+    // With the fail policy a tracking error fails the step, but only once
+    // the step files are cleaned up.
+    let tracked = sync_tracking_end(plugin, spank);
+
+    // Task timings live in podman_tmp_path, grab them before cleanup.
+    perf_collect(plugin);
+
+    let t0 = Instant::now();
     cleanup_fs_local(plugin, spank)?;
     sync_cleanup_fs_shared(plugin, spank)?;
+    perf_add(plugin, "cleanup", t0.elapsed());
 
-    Ok(())
+    // Errors are logged where they happen, the first one is returned once
+    // everything ran.
+    let hooked = hook_point_run(plugin, HookPoint::PostCleanup, None);
+    let summarized = track_perf_summary(plugin, spank);
+    log_close_debug_file();
+
+    tracked.and(hooked).and(summarized)
 }
 
 fn slurmstepd_load_config(
@@ -188,6 +253,16 @@ fn slurmstepd_load_config(
         }
     };
 
+    match setup_skybox_config(plugin, spank) {
+        Ok(_) => {}
+        Err(e) => {
+            plugin.config.skybox_enabled = false;
+            skybox_log_error!("{}", e);
+            skybox_log_error!("plugin is disabled");
+            return plugin_err("plugin is disabled");
+        }
+    }
+
     // Set Config
     match setup_config(&config, plugin) {
         Ok(_) => {}
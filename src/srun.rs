@@ -1,9 +1,12 @@
+use nix::unistd::{getgid, getuid};
 use std::error::Error;
 
 use slurm_spank::SpankHandle;
 
+use crate::admission::admission_enforce;
 use crate::args::*;
 use crate::config::*;
+use crate::constraints::edf_constraints_check;
 use crate::edf::*;
 use crate::{SpankSkyBox, plugin_err, skybox_log_error, skybox_log_user};
 use raster::*;
@@ -41,6 +44,16 @@ fn srun_load_config(
         }
     };
 
+    match setup_skybox_config(plugin, spank) {
+        Ok(_) => {}
+        Err(e) => {
+            plugin.config.skybox_enabled = false;
+            skybox_log_error!("{}", e);
+            skybox_log_error!("plugin is disabled");
+            return plugin_err("plugin is disabled");
+        }
+    }
+
     // Set Config
     match setup_config(&config, plugin) {
         Ok(_) => {}
@@ -84,6 +97,11 @@ pub(crate) fn srun_init_post_opt(
         return Ok(());
     }
 
+    if let Err(e) = admission_enforce(plugin, spank) {
+        skybox_log_user!("{e}");
+        return Err(e);
+    }
+
     match load_edf(plugin, spank) {
         Ok(o) => (),
         Err(e) => {
@@ -92,6 +110,18 @@ pub(crate) fn srun_init_post_opt(
         }
     }
 
+    if let Some(edf) = &plugin.edf
+        && let Err(e) = edf_constraints_check(
+            &plugin.skybox_config.constraints,
+            edf,
+            getuid().as_raw(),
+            getgid().as_raw(),
+        )
+    {
+        skybox_log_user!("{e}");
+        return Err(e);
+    }
+
     update_config_by_user(&mut plugin.config, plugin.edf.clone().unwrap())?;
     let _ = set_remaining_default_args(plugin)?;
 
@@ -1,15 +1,21 @@
+use nix::fcntl::{Flock, FlockArg};
+use nix::unistd::Pid;
+use std::collections::HashSet;
 use std::error::Error;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Write};
-use std::path::Path;
-use sysinfo::{Pid, ProcessStatus, System};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::time::Instant;
+use sysinfo::{ProcessStatus, System};
 
 use slurm_spank::SpankHandle;
 
+use crate::hooks::{HookPoint, hook_point_run};
+use crate::perf::perf_share;
 use crate::{
-    SpankSkyBox, get_local_task_id, plugin_err, plugin_string, podman::podman_pull,
-    podman::podman_start, podman::podman_stop, skybox_log_debug, skybox_log_error,
-    tracking::track_usage,
+    SpankSkyBox, get_local_task_id, plugin_err, plugin_string, podman::pid_parse,
+    podman::podman_pull, podman::podman_start, podman::podman_stop, skybox_log_debug,
+    skybox_log_error, tracking::track_usage, tracking::track_usage_end,
 };
 
 pub(crate) fn is_local_task_0(ssb: &mut SpankSkyBox, _spank: &mut SpankHandle) -> bool {
@@ -57,8 +63,8 @@ pub(crate) fn is_node_0(ssb: &mut SpankSkyBox, _spank: &mut SpankHandle) -> bool
     return false;
 }
 
-fn is_process_stopped(pid: usize) -> Result<bool, Box<dyn Error>> {
-    let p = Pid::from(pid);
+fn is_process_stopped(pid: Pid) -> Result<bool, Box<dyn Error>> {
+    let p = sysinfo::Pid::from(pid.as_raw() as usize);
 
     let s = System::new_all();
     let Some(process) = s.process(p) else {
@@ -78,7 +84,11 @@ pub(crate) fn sync_podman_pull(
     spank: &mut SpankHandle,
 ) -> Result<(), Box<dyn Error>> {
     if is_global_task_0(ssb, spank) {
-        match podman_pull(ssb, spank) {
+        let t0 = Instant::now();
+        let result = podman_pull(ssb, spank);
+        perf_share(ssb, "pull", t0.elapsed());
+
+        match result {
             Ok(_) => {
                 sync_podman_pull_done(ssb, spank, 0)?;
             }
@@ -174,6 +184,11 @@ pub(crate) fn sync_podman_start(
     }
     sync_podman_start_wait(ssb, spank)?;
 
+    if is_local_task_0(ssb, spank) {
+        let pid = ssb.run.as_ref().and_then(|r| r.pid);
+        hook_point_run(ssb, HookPoint::PostStart, pid)?;
+    }
+
     Ok(())
 }
 
@@ -226,7 +241,11 @@ pub(crate) fn sync_podman_start_wait(
         }
     }
 
-    let pid: usize = strpid.parse()?;
+    let Some(pid) = pid_parse(&strpid) else {
+        let msg = format!("container pidfile {pidfile} has no valid pid.");
+        skybox_log_error!("task {} - {msg}", get_local_task_id(ssb));
+        return plugin_err(&msg);
+    };
     attempts = 0;
     // Wait max 5 minutes for entrypoint
     max_attempts = 5 * 600;
@@ -257,7 +276,7 @@ pub(crate) fn sync_podman_start_wait(
     }
 
     let mut newrun = ssb.run.clone().unwrap();
-    newrun.pid = pid;
+    newrun.pid = Some(pid);
 
     ssb.run = Some(newrun);
 
@@ -269,71 +288,81 @@ pub(crate) fn sync_podman_stop(
     spank: &mut SpankHandle,
 ) -> Result<(), Box<dyn Error>> {
     let run = ssb.run.clone().unwrap();
-    let job = ssb.job.clone().unwrap();
-    let task_id = job.local_task_id;
-    let task_count = job.local_task_count;
-
-    // create sync folder if doesn't exist
-    let completed_dir_path = format!("{}/completed", run.podman_tmp_path);
-    if !std::path::Path::new(&completed_dir_path).exists() {
-        std::fs::create_dir_all(&completed_dir_path)?;
-    }
-
-    // touch file in sync folder
-    let completed_file_path = format!("{}/task_{}.exit", completed_dir_path, task_id);
-    File::create(completed_file_path)?;
 
-    // Wait for all tasks to stop podman.
-    let readdir = std::fs::read_dir(&completed_dir_path)?;
-    if (readdir.count() as u32) == task_count {
-        sync_cleanup_fs_local_dir_completed(ssb, spank)?;
+    // The exit record stays until podman_tmp_path is removed, so a late
+    // duplicate can't restart the count.
+    if sync_task_exit_is_last(ssb, spank)? {
+        // A failing pre_stop hook must not leave the container running.
+        let hooked = hook_point_run(ssb, HookPoint::PreStop, run.pid);
         podman_stop(ssb, spank)?;
+        hooked?;
     }
 
     Ok(())
 }
 
-pub(crate) fn sync_cleanup_fs_local_dir_completed(
+/// Record the exit of this task, true for exactly one caller: the task that
+/// completes the set of local tasks.
+///
+/// Task ids are appended to a file under an exclusive flock, so concurrent
+/// exits are serialized and a task reported twice is counted once.
+pub(crate) fn sync_task_exit_is_last(
     ssb: &mut SpankSkyBox,
     _spank: &mut SpankHandle,
-) -> Result<(), Box<dyn Error>> {
-    let base_path = match ssb.run.clone() {
-        Some(r) => r.podman_tmp_path,
-        None => {
-            return plugin_err("couldn't find podman_tmp_path");
+) -> Result<bool, Box<dyn Error>> {
+    let (run, job) = match (ssb.run.clone(), ssb.job.clone()) {
+        (Some(r), Some(j)) => (r, j),
+        _ => {
+            return Err(plugin_string("cannot find run or job structure").into());
         }
     };
 
-    let completed_dir_path = format!("{}/completed", base_path);
+    let exited_path = format!("{}/exited", run.podman_tmp_path);
+    exit_record(&exited_path, job.local_task_id, job.local_task_count)
+}
 
-    if !Path::new(&completed_dir_path).exists() {
-        ()
-    } else {
-        match std::fs::remove_dir_all(&completed_dir_path) {
-            Ok(_) => (),
-            Err(e) => {
-                let msg = format!(
-                    "couldn't cleanup \"{:#?}\", error {}",
-                    &completed_dir_path, e
-                );
-                return plugin_err(&msg);
-            }
-        };
-    }
-    /*
-    if !Path::new(&base_path).exists() {
-        ()
-    } else {
-        match std::fs::remove_dir_all(&base_path) {
-            Ok(_) => (),
-            Err(e) => {
-                let msg = format!("couldn't cleanup \"{:#?}\", error {}", &base_path, e);
-                return plugin_err(&msg);
-            }
-        };
+// The directory belongs to the job user, don't follow a link they put there.
+fn exit_record(exited_path: &str, task_id: u32, task_count: u32) -> Result<bool, Box<dyn Error>> {
+    let file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(exited_path)?;
+
+    let mut locked = match Flock::lock(file, FlockArg::LockExclusive) {
+        Ok(l) => l,
+        Err((_, e)) => {
+            let msg = format!("cannot lock {exited_path}: {e}");
+            return Err(plugin_string(&msg).into());
+        }
+    };
+
+    let mut content = String::new();
+    locked.read_to_string(&mut content)?;
+
+    let mut exited: HashSet<u32> = content
+        .lines()
+        .filter_map(|l| l.trim().parse::<u32>().ok())
+        .collect();
+
+    if !exited.insert(task_id) {
+        skybox_log_debug!("task {} - exit already recorded", task_id);
+        return Ok(false);
     }
-    */
-    Ok(())
+
+    writeln!(locked, "{}", task_id)?;
+    locked.flush()?;
+
+    skybox_log_debug!(
+        "task {} - {} of {} local tasks exited",
+        task_id,
+        exited.len(),
+        task_count
+    );
+
+    Ok(exited.len() as u32 == task_count)
 }
 
 pub(crate) fn sync_cleanup_fs_shared(
@@ -382,3 +411,70 @@ pub(crate) fn sync_tracking(
     }
     Ok(())
 }
+
+pub(crate) fn sync_tracking_end(
+    ssb: &mut SpankSkyBox,
+    spank: &mut SpankHandle,
+) -> Result<(), Box<dyn Error>> {
+    // Only this stepd knows the exit codes of its tasks, every node reports.
+    track_usage_end(ssb, spank)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestDir;
+    use std::sync::{Arc, Barrier};
+
+    #[test]
+    fn concurrent_exits_have_one_last() {
+        let dir = TestDir::new("sync-concurrent");
+        let tasks = 16;
+
+        for round in 0..10 {
+            let path = dir.path(&format!("exited-{round}"));
+            let barrier = Arc::new(Barrier::new(tasks as usize));
+
+            let threads: Vec<_> = (0..tasks)
+                .map(|id| {
+                    let path = path.clone();
+                    let barrier = barrier.clone();
+                    std::thread::spawn(move || {
+                        barrier.wait();
+                        exit_record(&path, id, tasks).unwrap()
+                    })
+                })
+                .collect();
+
+            let last = threads
+                .into_iter()
+                .map(|t| t.join().unwrap())
+                .filter(|l| *l)
+                .count();
+            assert_eq!(last, 1);
+        }
+    }
+
+    #[test]
+    fn duplicate_exit_counted_once() {
+        let dir = TestDir::new("sync-duplicate");
+        let path = dir.path("exited");
+
+        assert!(!exit_record(&path, 0, 3).unwrap());
+        assert!(!exit_record(&path, 1, 3).unwrap());
+        assert!(!exit_record(&path, 1, 3).unwrap());
+        assert!(exit_record(&path, 2, 3).unwrap());
+        assert!(!exit_record(&path, 2, 3).unwrap());
+        assert!(!exit_record(&path, 0, 3).unwrap());
+    }
+
+    #[test]
+    fn symlink_refused() {
+        let dir = TestDir::new("sync-symlink");
+        std::fs::write(dir.path("target"), "").unwrap();
+        std::os::unix::fs::symlink(dir.path("target"), dir.path("exited")).unwrap();
+
+        assert!(exit_record(&dir.path("exited"), 0, 1).is_err());
+        assert_eq!(std::fs::read_to_string(dir.path("target")).unwrap(), "");
+    }
+}
@@ -1,33 +1,101 @@
+use nix::errno::Errno;
+use nix::fcntl::{FcntlArg, Flock, FlockArg, OFlag, fcntl};
+use nix::sys::signal::{Signal, killpg};
+use nix::sys::wait::waitpid;
+use nix::unistd::{ForkResult, Pid, fork, setsid};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::error::Error;
-use std::io::{Write, pipe};
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Read, Write, pipe};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::UnixDatagram;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
 use std::process::{Command, Stdio};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
+use sysinfo::System;
 
 use slurm_spank::SpankHandle;
 
-use crate::{SpankSkyBox, plugin_string, spank_getenv};
+use crate::config::{FailPolicy, TrackingSinkKind};
+use crate::redact::Redactor;
+use crate::{
+    SpankSkyBox, get_plugin_name, plugin_string, skybox_log_debug, skybox_log_error,
+    skybox_log_info, skybox_log_user, spank_getenv,
+};
+
+/// Version of the tracking record layout, bump on incompatible changes.
+pub(crate) const TRACKING_SCHEMA_VERSION: u32 = 1;
 
 #[derive(Serialize, Deserialize, Debug)]
 struct Record {
+    schema_version: u32,
+    event: Event,
     account: String,
-    command: String,
+    argv: Vec<String>,
+    cluster: String,
     context: String,
     engine: String,
     environment: Environment,
     image: String,
+    image_digest: Option<String>,
     jobid: usize,
     nnodes: usize,
+    ntasks: usize,
+    nodeid: usize,
     nodelist: String,
     stepid: usize,
     user: String,
+    start_time: u64,
+    end_time: Option<u64>,
+    outcome: Option<Outcome>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Event {
+    Start,
+    End,
+    Perf,
+}
+
+/// Per node phase timings, emitted when the step ends on the node.
+#[derive(Serialize, Deserialize, Debug)]
+struct PerfRecord {
+    schema_version: u32,
+    event: Event,
+    jobid: usize,
+    stepid: usize,
+    nodeid: usize,
+    node: String,
+    image: String,
+    image_cache: Option<String>,
+    phases_ms: BTreeMap<String, u64>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct Environment {
     name: String,
+    hash: String,
     content: String,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+struct Outcome {
+    success: bool,
+    exit_code: i32,
+}
+
+pub(crate) fn unix_time_now() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => 0,
+    }
+}
+
 pub(crate) fn track_usage(
     ssb: &mut SpankSkyBox,
     spank: &mut SpankHandle,
@@ -35,16 +103,209 @@ pub(crate) fn track_usage(
     if !ssb.config.tracking_enabled {
         return Ok(());
     }
-    let data = track_slurmstepd_data_collect(ssb, spank)?;
-    track_send(data, ssb)?;
 
-    Ok(())
+    let result = track_slurmstepd_data_collect(ssb, spank, Event::Start)
+        .and_then(|record| Ok(serde_json::to_string(&record)?))
+        .and_then(|data| track_send(data, ssb));
+
+    track_apply_fail_policy(ssb, result)
 }
 
-pub(crate) fn track_slurmstepd_data_collect(
+pub(crate) fn track_usage_end(
     ssb: &mut SpankSkyBox,
     spank: &mut SpankHandle,
-) -> Result<String, Box<dyn Error>> {
+) -> Result<(), Box<dyn Error>> {
+    if !ssb.config.tracking_enabled {
+        return Ok(());
+    }
+
+    let exit_code = match &ssb.job {
+        Some(j) => j.exit_code.unwrap_or(0),
+        None => 0,
+    };
+
+    let result = track_slurmstepd_data_collect(ssb, spank, Event::End)
+        .and_then(|mut record| {
+            record.end_time = Some(unix_time_now());
+            record.outcome = Some(Outcome {
+                success: exit_code == 0,
+                exit_code,
+            });
+            Ok(serde_json::to_string(&record)?)
+        })
+        .and_then(|data| track_send(data, ssb));
+
+    track_apply_fail_policy(ssb, result)
+}
+
+pub(crate) fn track_perf_summary(
+    ssb: &mut SpankSkyBox,
+    _spank: &mut SpankHandle,
+) -> Result<(), Box<dyn Error>> {
+    if !ssb.config.tracking_enabled && !ssb.config.perfmon {
+        return Ok(());
+    }
+
+    let (job, edf) = match (&ssb.job, &ssb.edf) {
+        (Some(j), Some(e)) => (j, e),
+        _ => return Ok(()),
+    };
+
+    let record = PerfRecord {
+        schema_version: TRACKING_SCHEMA_VERSION,
+        event: Event::Perf,
+        jobid: job.jobid.try_into()?,
+        stepid: job.stepid.try_into()?,
+        nodeid: job.nodeid.try_into()?,
+        node: System::host_name().unwrap_or_default(),
+        image: edf.image.clone(),
+        image_cache: ssb.perf.image_cache.clone(),
+        phases_ms: ssb.perf.phases.clone(),
+    };
+
+    if ssb.config.perfmon {
+        let phases: Vec<String> = record
+            .phases_ms
+            .iter()
+            .map(|(k, v)| format!("{k}={v}ms"))
+            .collect();
+        skybox_log_user!(
+            "skybox-perf: node {} image cache {} {}",
+            record.node,
+            record
+                .image_cache
+                .clone()
+                .unwrap_or(String::from("unknown")),
+            phases.join(" ")
+        );
+    }
+
+    if !ssb.config.tracking_enabled {
+        return Ok(());
+    }
+
+    let result = serde_json::to_string(&record)
+        .map_err(|e| e.into())
+        .and_then(|data| track_send(data, ssb));
+
+    track_apply_fail_policy(ssb, result)
+}
+
+// Tracking must not break jobs unless the site asks for it.
+fn track_apply_fail_policy(
+    ssb: &SpankSkyBox,
+    result: Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let e = match result {
+        Ok(_) => return Ok(()),
+        Err(e) => e,
+    };
+
+    match ssb.skybox_config.tracking.fail_policy {
+        FailPolicy::Fail => {
+            skybox_log_error!("tracking failed: {e}");
+            Err(e)
+        }
+        FailPolicy::Warn => {
+            skybox_log_info!("warning: tracking failed: {e}");
+            Ok(())
+        }
+        FailPolicy::Ignore => {
+            skybox_log_debug!("tracking failed: {e}");
+            Ok(())
+        }
+    }
+}
+
+// Keep the first failing exit code of the tasks of this node, signals map
+// to 128+N.
+pub(crate) fn track_task_exit(ssb: &mut SpankSkyBox, spank: &mut SpankHandle) {
+    let status = match spank.task_exit_status() {
+        Ok(s) => s,
+        Err(_) => return,
+    };
+
+    let exit_code = if libc::WIFEXITED(status) {
+        libc::WEXITSTATUS(status)
+    } else if libc::WIFSIGNALED(status) {
+        128 + libc::WTERMSIG(status)
+    } else {
+        status
+    };
+
+    if let Some(job) = ssb.job.as_mut()
+        && job.exit_code.unwrap_or(0) == 0
+    {
+        job.exit_code = Some(exit_code);
+    }
+}
+
+/// Manifest digest of the image: the one of a digest reference, otherwise
+/// the one recorded by the imagestore for the image name.
+fn image_digest(imagestore: &str, image: &str) -> Option<String> {
+    if let Some((_, digest)) = image.split_once('@') {
+        return Some(String::from(digest));
+    }
+
+    let path = format!("{imagestore}/overlay-images/images.json");
+    let content = match std::fs::read_to_string(&path) {
+        Ok(c) => c,
+        Err(e) => {
+            skybox_log_debug!("cannot read {path}: {e}");
+            return None;
+        }
+    };
+    image_digest_lookup(&content, &image_reference_normalize(image))
+}
+
+// images.json of containers storage, a list of images with their names.
+fn image_digest_lookup(images_json: &str, name: &str) -> Option<String> {
+    let images: Vec<serde_json::Value> = serde_json::from_str(images_json).ok()?;
+
+    images
+        .iter()
+        .find(|i| match i.get("names").and_then(|n| n.as_array()) {
+            Some(names) => names.iter().any(|n| n.as_str() == Some(name)),
+            None => false,
+        })
+        .and_then(|i| i.get("digest")?.as_str().map(String::from))
+}
+
+// Full name as stored by podman: "ubuntu" is "docker.io/library/ubuntu:latest".
+fn image_reference_normalize(image: &str) -> String {
+    let (registry, mut path) = match image.split_once('/') {
+        Some((first, rest))
+            if first.contains('.') || first.contains(':') || first == "localhost" =>
+        {
+            (first, String::from(rest))
+        }
+        _ => ("docker.io", String::from(image)),
+    };
+
+    if registry == "docker.io" && !path.contains('/') {
+        path = format!("library/{path}");
+    }
+
+    let name = path.rsplit('/').next().unwrap_or_default();
+    if !name.contains(':') {
+        path.push_str(":latest");
+    }
+
+    format!("{registry}/{path}")
+}
+
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn track_slurmstepd_data_collect(
+    ssb: &mut SpankSkyBox,
+    spank: &mut SpankHandle,
+    event: Event,
+) -> Result<Record, Box<dyn Error>> {
     let edf = match &ssb.edf {
         Some(o) => o,
         None => {
@@ -61,6 +322,8 @@ pub(crate) fn track_slurmstepd_data_collect(
 
     let jobid: usize = job.jobid.try_into()?;
     let stepid: usize = job.stepid.try_into()?;
+    let ntasks: usize = job.total_task_count.try_into()?;
+    let nodeid: usize = job.nodeid.try_into()?;
 
     let env_name = match &ssb.args.edf {
         Some(o) => o.to_string(),
@@ -68,65 +331,406 @@ pub(crate) fn track_slurmstepd_data_collect(
             return Err(plugin_string("couldn't find args.edf").into());
         }
     };
-    let env_content = spank_getenv(spank, "SLURM_EDF_EXPANDED");
+    let redactor = Redactor::new(&ssb.skybox_config.tracking.redact)?;
+    // The EDF that ran, SLURM_EDF_EXPANDED may be missing or differ from the
+    // rendering of the nodes.
+    let env_content = edf.to_toml_string()?;
 
+    // Hash what is sent, a hash of a secret could be brute forced.
+    let content = redactor.redact_toml(&env_content);
     let environment = Environment {
         name: env_name,
-        content: env_content,
+        hash: sha256_hex(content.as_bytes()),
+        content,
     };
 
     let account = spank_getenv(spank, "SLURM_JOB_ACCOUNT");
 
     let argv = match spank.job_argv() {
-        Ok(a) => a,
+        Ok(a) => redactor.redact_argv(&a),
         Err(_) => {
             return Err(plugin_string("couldn't read job_argv").into());
         }
     };
 
-    let mut command = String::from("");
-    for i in argv.iter() {
-        if command != "" {
-            command.push_str(" ");
-        }
-        command.push_str(i);
-    }
-
+    let cluster = spank_getenv(spank, "SLURM_CLUSTER_NAME");
     let nnodes = spank_getenv(spank, "SLURM_NNODES").parse::<usize>()?;
     let nodelist = spank_getenv(spank, "SLURM_NODELIST");
     let user = spank_getenv(spank, "SLURM_JOB_USER");
 
     let record = Record {
+        schema_version: TRACKING_SCHEMA_VERSION,
+        event,
         account: account,
-        command: command,
+        argv,
+        cluster,
         context: String::from("slurmstepd"),
         engine: String::from("podman"),
         environment: environment,
         image: edf.image.clone(),
+        image_digest: image_digest(&ssb.config.parallax_imagestore, &edf.image),
         jobid: jobid,
         nnodes: nnodes,
+        ntasks,
+        nodeid,
         nodelist: nodelist,
         stepid: stepid,
         user: user,
+        start_time: job.start_time,
+        end_time: None,
+        outcome: None,
     };
 
-    let s = serde_json::to_string(&record)?;
-    Ok(s)
+    Ok(record)
+}
+
+/// A destination for tracking records.
+pub(crate) trait TrackingSink {
+    fn name(&self) -> &str;
+    fn send(&self, data: &str, timeout: Duration) -> Result<(), Box<dyn Error>>;
+}
+
+/// Spawn `tracking_tool` and write the record to its stdin.
+struct CommandSink {
+    tool: String,
+}
+
+impl TrackingSink for CommandSink {
+    fn name(&self) -> &str {
+        "command"
+    }
+
+    fn send(&self, data: &str, timeout: Duration) -> Result<(), Box<dyn Error>> {
+        let (input_reader, input_writer) = pipe()?;
+
+        // Own process group, a timeout takes the background children of the tool too.
+        let mut child = Command::new(&self.tool)
+            .stdin(input_reader)
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .process_group(0)
+            .spawn()?;
+        let group = Pid::from_raw(child.id() as i32);
+
+        // Drive both pipes from here without blocking, a tool that doesn't read
+        // its stdin must not block us and no thread outlives the call.
+        let mut input = Some(input_writer);
+        let mut stderr_pipe = child.stderr.take();
+        if let Some(w) = input.as_ref() {
+            fcntl(w, FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+        }
+        if let Some(r) = stderr_pipe.as_ref() {
+            fcntl(r, FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+        }
+
+        let mut pending = data.as_bytes();
+        let mut stderr_buf = vec![];
+        let mut status = None;
+        let deadline = Instant::now() + timeout;
+        let status = loop {
+            if status.is_none() {
+                status = child.try_wait()?;
+            }
+
+            // Stop writing once the tool is gone, nobody reads the pipe anymore.
+            if let Some(w) = input.as_mut() {
+                if status.is_none() && !pending.is_empty() {
+                    match w.write(pending) {
+                        Ok(n) => pending = &pending[n..],
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+                        Err(_) => pending = &[],
+                    }
+                }
+                if status.is_some() || pending.is_empty() {
+                    input = None;
+                }
+            }
+
+            if let Some(r) = stderr_pipe.as_mut() {
+                let mut chunk = [0u8; 4096];
+                loop {
+                    match r.read(&mut chunk) {
+                        Ok(0) => {
+                            stderr_pipe = None;
+                            break;
+                        }
+                        Ok(n) => stderr_buf.extend_from_slice(&chunk[..n]),
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                        Err(_) => {
+                            stderr_pipe = None;
+                            break;
+                        }
+                    }
+                }
+            }
+
+            match status {
+                // A background child of the tool may keep stderr open.
+                Some(s) if stderr_pipe.is_none() || Instant::now() >= deadline => break s,
+                Some(_) => (),
+                None if Instant::now() >= deadline => {
+                    let _ = killpg(group, Signal::SIGKILL);
+                    let _ = child.wait();
+                    return Err(plugin_string(&format!("{} timed out", self.tool)).into());
+                }
+                None => (),
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+
+        let stderr = String::from_utf8_lossy(&stderr_buf).into_owned();
+        skybox_log_debug!("{} exit code: {}", self.tool, status);
+        for line in stderr.lines() {
+            skybox_log_debug!("{} stderr: {}", self.tool, line);
+        }
+
+        if !status.success() {
+            let msg = match stderr.lines().next() {
+                Some(l) => format!("{} exited with {status}: {l}", self.tool),
+                None => format!("{} exited with {status}", self.tool),
+            };
+            return Err(plugin_string(&msg).into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Append the record as one line to a JSONL file, serialized with flock.
+struct FileSink {
+    path: PathBuf,
+}
+
+impl TrackingSink for FileSink {
+    fn name(&self) -> &str {
+        "file"
+    }
+
+    fn send(&self, data: &str, timeout: Duration) -> Result<(), Box<dyn Error>> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o640)
+            .open(&self.path)?;
+
+        let deadline = Instant::now() + timeout;
+        let mut locked = loop {
+            match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+                Ok(l) => break l,
+                Err((f, Errno::EWOULDBLOCK)) => {
+                    if Instant::now() >= deadline {
+                        let msg = format!("timed out locking {}", self.path.display());
+                        return Err(plugin_string(&msg).into());
+                    }
+                    file = f;
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                Err((_, e)) => return Err(Box::new(e)),
+            }
+        };
+
+        let line = format!("{data}\n");
+        locked.write_all(line.as_bytes())?;
+
+        Ok(())
+    }
+}
+
+/// Send the record to a local datagram socket, syslog is /dev/log.
+struct SocketSink {
+    path: PathBuf,
+    syslog: bool,
+}
+
+impl TrackingSink for SocketSink {
+    fn name(&self) -> &str {
+        if self.syslog { "syslog" } else { "socket" }
+    }
+
+    fn send(&self, data: &str, timeout: Duration) -> Result<(), Box<dyn Error>> {
+        let socket = UnixDatagram::unbound()?;
+        socket.set_write_timeout(Some(timeout))?;
+
+        let message = if self.syslog {
+            // <PRI> for facility user, severity info
+            format!(
+                "<14>{}[{}]: {}",
+                get_plugin_name(),
+                std::process::id(),
+                data
+            )
+        } else {
+            String::from(data)
+        };
+
+        socket.send_to(message.as_bytes(), &self.path)?;
+        Ok(())
+    }
+}
+
+pub(crate) fn tracking_sink(ssb: &SpankSkyBox) -> Box<dyn TrackingSink> {
+    let tracking = &ssb.skybox_config.tracking;
+
+    match tracking.sink {
+        TrackingSinkKind::Command => Box::new(CommandSink {
+            tool: ssb.config.tracking_tool.clone(),
+        }),
+        TrackingSinkKind::File => Box::new(FileSink {
+            path: PathBuf::from(&tracking.path),
+        }),
+        TrackingSinkKind::Syslog => Box::new(SocketSink {
+            path: PathBuf::from("/dev/log"),
+            syslog: true,
+        }),
+        TrackingSinkKind::Socket => Box::new(SocketSink {
+            path: PathBuf::from(&tracking.path),
+            syslog: false,
+        }),
+    }
 }
 
 pub(crate) fn track_send(data: String, ssb: &mut SpankSkyBox) -> Result<(), Box<dyn Error>> {
-    let tracking_tool = &ssb.config.tracking_tool;
-    let (input_reader, mut input_writer) = pipe()?;
+    let sink = tracking_sink(ssb);
+    let tracking = &ssb.skybox_config.tracking;
+    let timeout = Duration::from_millis(tracking.timeout_ms);
+
+    skybox_log_debug!("sending tracking record to {} sink", sink.name());
+
+    // Only a failing policy needs the result before the step goes on, every
+    // sink gives up after the timeout.
+    if tracking.fail_policy == FailPolicy::Fail {
+        return match sink.send(&data, timeout) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                let msg = format!("{} sink: {e}", sink.name());
+                Err(plugin_string(&msg).into())
+            }
+        };
+    }
+
+    track_send_detached(sink, data, timeout, tracking.fail_policy)
+}
+
+// Send from a double forked process. The caller only reaps the intermediate
+// child, which exits at once, so it neither waits on the sink nor leaves a
+// zombie behind once the task execs the user program. The sender still gives
+// up after the timeout.
+fn track_send_detached(
+    sink: Box<dyn TrackingSink>,
+    data: String,
+    timeout: Duration,
+    policy: FailPolicy,
+) -> Result<(), Box<dyn Error>> {
+    match unsafe { fork() }? {
+        ForkResult::Parent { child } => {
+            waitpid(child, None)?;
+            return Ok(());
+        }
+        ForkResult::Child => (),
+    }
+
+    let _ = setsid();
+    match unsafe { fork() } {
+        Ok(ForkResult::Child) => (),
+        _ => unsafe { libc::_exit(0) },
+    }
 
-    let mut child = Command::new(tracking_tool)
-        .stdin(input_reader)
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .spawn()?;
+    let rc = match sink.send(&data, timeout) {
+        Ok(_) => 0,
+        Err(e) => {
+            if policy == FailPolicy::Warn {
+                skybox_log_info!("warning: tracking failed: {} sink: {e}", sink.name());
+            } else {
+                skybox_log_debug!("tracking failed: {} sink: {e}", sink.name());
+            }
+            1
+        }
+    };
+
+    unsafe { libc::_exit(rc) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestDir;
+
+    #[test]
+    fn reference_normalize() {
+        let cases = [
+            ("ubuntu", "docker.io/library/ubuntu:latest"),
+            ("ubuntu:24.04", "docker.io/library/ubuntu:24.04"),
+            ("nvidia/cuda:12.4", "docker.io/nvidia/cuda:12.4"),
+            (
+                "docker.io/library/alpine",
+                "docker.io/library/alpine:latest",
+            ),
+            (
+                "quay.io/centos/centos:stream9",
+                "quay.io/centos/centos:stream9",
+            ),
+            ("localhost:5000/img", "localhost:5000/img:latest"),
+            ("localhost/img:1", "localhost/img:1"),
+        ];
+        for (image, name) in cases {
+            assert_eq!(image_reference_normalize(image), name, "{image}");
+        }
+    }
+
+    #[test]
+    fn digest_reference() {
+        assert_eq!(
+            image_digest("/nonexistent", "ubuntu@sha256:abcd"),
+            Some(String::from("sha256:abcd"))
+        );
+    }
 
-    input_writer.write_all(data.as_bytes())?;
-    drop(input_writer);
-    child.wait()?;
+    #[test]
+    fn digest_lookup() {
+        let images = r#"[
+            {"id": "1", "digest": "sha256:aaa", "names": ["docker.io/library/ubuntu:24.04"]},
+            {"id": "2", "digest": "sha256:bbb", "names": ["quay.io/a/b:1", "quay.io/a/b:latest"]},
+            {"id": "3", "names": ["docker.io/library/alpine:latest"]}
+        ]"#;
 
-    Ok(())
+        let lookup = |image| image_digest_lookup(images, &image_reference_normalize(image));
+        assert_eq!(lookup("ubuntu:24.04"), Some(String::from("sha256:aaa")));
+        assert_eq!(lookup("quay.io/a/b"), Some(String::from("sha256:bbb")));
+        assert_eq!(lookup("alpine"), None);
+        assert_eq!(lookup("ubuntu"), None);
+        assert_eq!(image_digest_lookup("not json", "x"), None);
+    }
+
+    fn command_sink(name: &str, script: &str) -> (CommandSink, TestDir) {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TestDir::new(&format!("tracking-{name}"));
+        dir.write("tool", script);
+        let tool = dir.path("tool");
+        std::fs::set_permissions(&tool, std::fs::Permissions::from_mode(0o755)).unwrap();
+        (CommandSink { tool }, dir)
+    }
+
+    #[test]
+    fn command_sink_bounded() {
+        let timeout = Duration::from_millis(500);
+
+        let (sink, _dir) = command_sink("ok", "#!/bin/sh\ncat > /dev/null\n");
+        assert!(sink.send("{}", timeout).is_ok());
+
+        let (sink, _dir) = command_sink("fail", "#!/bin/sh\necho broken >&2\nexit 3\n");
+        let e = sink.send("{}", timeout).unwrap_err().to_string();
+        assert!(e.contains("broken"), "{e}");
+
+        // Neither a hung tool nor a background child holding stderr keeps us past the timeout.
+        let (sink, _dir) = command_sink("hang", "#!/bin/sh\nexec sleep 30\n");
+        let start = Instant::now();
+        assert!(sink.send(&"x".repeat(1 << 20), timeout).is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let (sink, _dir) = command_sink("background", "#!/bin/sh\nsleep 3 >&2 &\n");
+        let start = Instant::now();
+        assert!(sink.send("{}", timeout).is_ok());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
 }
@@ -0,0 +1,253 @@
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use nix::sys::statfs::{TMPFS_MAGIC, statfs};
+use nix::unistd::{AccessFlags, access};
+use raster::Config;
+
+use crate::config::{ConfigLayers, JobInfoSource, SkyBoxConfig, TrackingSinkKind};
+use crate::redact::Redactor;
+
+/// Problems found in the configuration of a node, and what the configuration
+/// layers override.
+#[derive(Default)]
+pub(crate) struct ValidationReport {
+    pub(crate) errors: Vec<String>,
+    pub(crate) warnings: Vec<String>,
+    pub(crate) layers: Vec<String>,
+}
+
+/// Check the whole configuration against the node, collecting every problem
+/// instead of stopping at the first one.
+pub(crate) fn config_validate(config: &Config, skybox: &SkyBoxConfig) -> ValidationReport {
+    let mut report = ValidationReport::default();
+
+    let required = [
+        ("parallax_imagestore", &config.parallax_imagestore),
+        ("parallax_mount_program", &config.parallax_mount_program),
+        ("parallax_path", &config.parallax_path),
+        ("podman_module", &config.podman_module),
+        ("podman_path", &config.podman_path),
+        ("podman_tmp_path", &config.podman_tmp_path),
+    ];
+    for (key, value) in required.iter() {
+        if value.is_empty() {
+            report.errors.push(format!("{key} is not set"));
+        }
+    }
+
+    let binaries = [
+        ("podman_path", &config.podman_path),
+        ("parallax_path", &config.parallax_path),
+        ("parallax_mount_program", &config.parallax_mount_program),
+        (
+            "parallax_mp_squashfuse_path",
+            &config.parallax_mp_squashfuse_path,
+        ),
+    ];
+    for (key, value) in binaries.iter() {
+        if !value.is_empty() {
+            check_executable(&mut report, key, value);
+        }
+    }
+
+    check_imagestore(&mut report, &config.parallax_imagestore);
+    check_tmp_path(&mut report, &config.podman_tmp_path);
+    check_layers(&mut report, config, &skybox.layers);
+
+    if skybox.job_info.source == JobInfoSource::Slurmctld {
+        check_executable(
+            &mut report,
+            "[skybox.job_info] scontrol",
+            &skybox.job_info.scontrol,
+        );
+    }
+
+    if config.tracking_enabled {
+        let tracking = &skybox.tracking;
+        match tracking.sink {
+            TrackingSinkKind::Command => {
+                if config.tracking_tool.is_empty() {
+                    report.errors.push(String::from("tracking_tool is not set"));
+                } else {
+                    check_executable(&mut report, "tracking_tool", &config.tracking_tool);
+                }
+            }
+            TrackingSinkKind::File => match Path::new(&tracking.path).parent() {
+                Some(dir) if !tracking.path.is_empty() && dir.is_dir() => (),
+                _ => report.errors.push(format!(
+                    "[skybox.tracking] path \"{}\" is not in an existing directory",
+                    tracking.path
+                )),
+            },
+            TrackingSinkKind::Socket => {
+                if !Path::new(&tracking.path).exists() {
+                    report.errors.push(format!(
+                        "[skybox.tracking] socket \"{}\" does not exist",
+                        tracking.path
+                    ));
+                }
+            }
+            TrackingSinkKind::Syslog => (),
+        }
+
+        if let Err(e) = Redactor::new(&tracking.redact) {
+            report
+                .errors
+                .push(format!("[skybox.tracking.redact] invalid pattern: {e}"));
+        }
+    }
+
+    if !skybox.debug_log_dir.is_empty() && !Path::new(&skybox.debug_log_dir).is_dir() {
+        report.warnings.push(format!(
+            "debug_log_dir \"{}\" does not exist",
+            skybox.debug_log_dir
+        ));
+    }
+
+    report
+}
+
+// Values may still hold job variables at slurmd start, check the part
+// before the first one.
+fn static_prefix(path: &str) -> Option<&str> {
+    match path.find('$') {
+        None => Some(path),
+        Some(i) => match path[..i].rfind('/') {
+            Some(0) | None => None,
+            Some(j) => Some(&path[..j]),
+        },
+    }
+}
+
+// Layers apply per job, list what they override and catch unknown keys at
+// slurmd start rather than in the jobs.
+fn check_layers(report: &mut ValidationReport, config: &Config, layers: &ConfigLayers) {
+    let known = match toml::Value::try_from(config) {
+        Ok(toml::Value::Table(t)) => t,
+        _ => return,
+    };
+
+    let mut named: Vec<(String, &toml::Table)> = vec![];
+    for (name, layer) in layers.partition.iter() {
+        named.push((format!("partition {name}"), layer));
+    }
+    for (name, layer) in layers.account.iter() {
+        named.push((format!("account {name}"), layer));
+    }
+    named.sort_by(|a, b| a.0.cmp(&b.0));
+
+    for (source, layer) in named.iter() {
+        let keys: Vec<&str> = layer
+            .keys()
+            .map(|k| k.as_str())
+            .filter(|k| *k != "skybox")
+            .collect();
+        for key in keys.iter().filter(|k| !known.contains_key(**k)) {
+            report
+                .errors
+                .push(format!("layer {source}: unknown configuration key {key}"));
+        }
+        report
+            .layers
+            .push(format!("{source} sets {}", keys.join(", ")));
+    }
+
+    if layers.user_keys.is_empty() || layers.user_file.is_empty() {
+        return;
+    }
+    for key in layers.user_keys.iter().filter(|k| !known.contains_key(*k)) {
+        report.errors.push(format!(
+            "[skybox.layers] user_keys: unknown configuration key {key}"
+        ));
+    }
+    report.layers.push(format!(
+        "$HOME/{} may set {}",
+        layers.user_file,
+        layers.user_keys.join(", ")
+    ));
+}
+
+fn check_executable(report: &mut ValidationReport, key: &str, value: &str) {
+    let path = if value.contains('/') {
+        Some(String::from(value))
+    } else {
+        // Bare names are looked up in PATH, like the commands are run.
+        std::env::var("PATH").ok().and_then(|p| {
+            p.split(':')
+                .map(|d| format!("{d}/{value}"))
+                .find(|c| Path::new(c).is_file())
+        })
+    };
+
+    let path = match path {
+        Some(p) => p,
+        None => {
+            report
+                .errors
+                .push(format!("{key} \"{value}\" not found in PATH"));
+            return;
+        }
+    };
+
+    match std::fs::metadata(&path) {
+        Ok(m) if !m.is_file() => {
+            report
+                .errors
+                .push(format!("{key} \"{path}\" is not a file"));
+        }
+        Ok(m) if m.permissions().mode() & 0o111 == 0 => {
+            report
+                .errors
+                .push(format!("{key} \"{path}\" is not executable"));
+        }
+        Ok(_) => (),
+        Err(e) => report.errors.push(format!("{key} \"{path}\": {e}")),
+    }
+}
+
+fn check_imagestore(report: &mut ValidationReport, imagestore: &str) {
+    let path = match static_prefix(imagestore) {
+        Some(p) if !p.is_empty() => p,
+        _ => return,
+    };
+
+    if !Path::new(path).exists() {
+        report.warnings.push(format!(
+            "parallax_imagestore \"{path}\" does not exist, it is created by the first job"
+        ));
+        return;
+    }
+
+    if access(path, AccessFlags::W_OK).is_err() {
+        report
+            .errors
+            .push(format!("parallax_imagestore \"{path}\" is not writable"));
+    }
+}
+
+fn check_tmp_path(report: &mut ValidationReport, tmp_path: &str) {
+    let path = match static_prefix(tmp_path) {
+        Some(p) if !p.is_empty() => p,
+        _ => return,
+    };
+
+    if !Path::new(path).is_dir() {
+        report
+            .errors
+            .push(format!("podman_tmp_path \"{path}\" is not a directory"));
+        return;
+    }
+
+    match statfs(path) {
+        Ok(s) if s.filesystem_type() != TMPFS_MAGIC => {
+            report.warnings.push(format!(
+                "podman_tmp_path \"{path}\" is not on tmpfs, container storage may be slow or persist"
+            ));
+        }
+        Ok(_) => (),
+        Err(e) => report.warnings.push(format!(
+            "cannot check filesystem of podman_tmp_path \"{path}\": {e}"
+        )),
+    }
+}